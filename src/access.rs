@@ -0,0 +1,78 @@
+//! Permission checks against the owner/group/other mode bits of an inode,
+//! or its POSIX access ACL when it has one.
+
+use libc::{c_int, EACCES, EIO, EPERM};
+
+use acl::{Acl, ACL_ACCESS};
+use inode::InodeRow;
+use CrustFS;
+
+pub static R_OK:u32 = 4;
+pub static W_OK:u32 = 2;
+pub static X_OK:u32 = 1;
+
+/// Can uid/gid do everything in mask (a combination of R_OK, W_OK and X_OK)?
+/// FUSE only tells us the primary gid, so supplementary groups aren't considered.
+pub fn permitted(row: &InodeRow, uid: u32, gid: u32, mask: u32) -> bool {
+    if uid == 0 {
+        //root can do anything, except execute a file nobody has execute permission on
+        return mask & X_OK == 0 || row.is_dir() || row.perm & 0o111 != 0;
+    }
+    let bits = if uid == row.uid {
+        (row.perm >> 6) & 7
+    } else if gid == row.gid {
+        (row.perm >> 3) & 7
+    } else {
+        row.perm & 7
+    };
+    bits & mask == mask
+}
+
+/// Owner-only changes such as chmod or setting explicit times: the owner or root.
+pub fn owns(row: &InodeRow, uid: u32) -> bool {
+    uid == 0 || uid == row.uid
+}
+
+impl CrustFS {
+    /// May uid/gid change the size of row? fh is the handle of an ftruncate;
+    /// one opened for writing is enough, as it was checked when opened.
+    pub fn check_truncate(&self, row: &InodeRow, uid: u32, gid: u32, fh: Option<u64>) -> Result<(), c_int> {
+        if fh.and_then(|fh| self.handles.get(&fh)).map(|handle| handle.writable()).unwrap_or(false) {
+            return Ok(());
+        }
+        if try!(self.check_access(row, uid, gid, W_OK)) {Ok(())} else {Err(EACCES)}
+    }
+
+    /// May uid/gid set the times of row, as utimensat(2) has it? Setting them
+    /// to now only takes write permission, any other time takes the owner.
+    pub fn check_set_times(&self, row: &InodeRow, uid: u32, gid: u32, only_now: bool) -> Result<(), c_int> {
+        if owns(row, uid) {
+            return Ok(());
+        }
+        if !only_now {
+            return Err(EPERM);
+        }
+        if try!(self.check_access(row, uid, gid, W_OK)) {Ok(())} else {Err(EACCES)}
+    }
+
+    /// Like permitted, but an access ACL on the inode takes the place of its mode bits.
+    pub fn check_access(&self, row: &InodeRow, uid: u32, gid: u32, mask: u32) -> Result<bool, c_int> {
+        if uid == 0 || mask == 0 {
+            return Ok(permitted(row, uid, gid, mask));
+        }
+        match self.select_xattr(row.inode, ACL_ACCESS) {
+            Ok(None) => Ok(permitted(row, uid, gid, mask)),
+            Ok(Some(bytes)) => match Acl::parse(&bytes) {
+                Ok(acl) => Ok(acl.permits(row.uid, row.gid, uid, gid, mask)),
+                Err(_) => {
+                    error!("check_access: corrupt ACL on inode {}, falling back to mode bits", row.inode);
+                    Ok(permitted(row, uid, gid, mask))
+                }
+            },
+            Err(err) => {
+                error!("check_access: unable to read ACL of inode {}: {:?}", row.inode, err);
+                Err(EIO)
+            }
+        }
+    }
+}
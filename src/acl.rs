@@ -0,0 +1,213 @@
+//! POSIX ACLs, kept in the system.posix_acl_access and system.posix_acl_default
+//! xattrs in the same little endian format the Linux VFS hands us through
+//! setxattr: a u32 version (2) followed by {u16 tag, u16 perm, u32 id} entries.
+
+use libc::{c_int, EINVAL};
+
+pub static ACL_ACCESS:&'static str = "system.posix_acl_access";
+pub static ACL_DEFAULT:&'static str = "system.posix_acl_default";
+
+static ACL_VERSION:u32 = 2;
+
+pub static ACL_USER_OBJ:u16 = 0x01;
+pub static ACL_USER:u16 = 0x02;
+pub static ACL_GROUP_OBJ:u16 = 0x04;
+pub static ACL_GROUP:u16 = 0x08;
+pub static ACL_MASK:u16 = 0x10;
+pub static ACL_OTHER:u16 = 0x20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AclEntry {
+    pub tag:u16,
+    pub perm:u16,
+    pub id:u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    pub entries:Vec<AclEntry>,
+}
+
+fn read_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    read_u16(bytes) as u32 | (read_u16(&bytes[2..]) as u32) << 16
+}
+
+fn write_u16(out: &mut Vec<u8>, value: u16) {
+    out.push(value as u8);
+    out.push((value >> 8) as u8);
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    write_u16(out, value as u16);
+    write_u16(out, (value >> 16) as u16);
+}
+
+impl Acl {
+    /// Parse and validate an ACL. It must have exactly one USER_OBJ, GROUP_OBJ
+    /// and OTHER entry, and a MASK whenever there are named USER or GROUP entries.
+    pub fn parse(bytes: &[u8]) -> Result<Acl, c_int> {
+        if bytes.len() < 4 || (bytes.len() - 4) % 8 != 0 || read_u32(bytes) != ACL_VERSION {
+            return Err(EINVAL);
+        }
+        let entries:Vec<AclEntry> = bytes[4..].chunks(8).map(|entry| AclEntry{
+            tag:read_u16(entry),
+            perm:read_u16(&entry[2..]),
+            id:read_u32(&entry[4..]),
+        }).collect();
+        let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+        if count(ACL_USER_OBJ) != 1 || count(ACL_GROUP_OBJ) != 1 || count(ACL_OTHER) != 1 || count(ACL_MASK) > 1 {
+            return Err(EINVAL);
+        }
+        if (count(ACL_USER) > 0 || count(ACL_GROUP) > 0) && count(ACL_MASK) == 0 {
+            return Err(EINVAL);
+        }
+        let known = ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER;
+        if entries.iter().any(|e| e.tag & known == 0 || e.tag.count_ones() != 1 || e.perm > 7) {
+            return Err(EINVAL);
+        }
+        Ok(Acl{entries:entries})
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + 8 * self.entries.len());
+        write_u32(&mut out, ACL_VERSION);
+        for entry in self.entries.iter() {
+            write_u16(&mut out, entry.tag);
+            write_u16(&mut out, entry.perm);
+            write_u32(&mut out, entry.id);
+        }
+        out
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+
+    /// The rwx bits chmod should show for this ACL. When there is a mask it
+    /// takes the place of the group bits.
+    pub fn mode(&self) -> u32 {
+        let group = self.perm(ACL_MASK).or(self.perm(ACL_GROUP_OBJ)).unwrap_or(0);
+        (self.perm(ACL_USER_OBJ).unwrap_or(0) as u32) << 6 | (group as u32) << 3 | self.perm(ACL_OTHER).unwrap_or(0) as u32
+    }
+
+    /// This ACL after chmod to mode: the owner, mask (or, without one, owning
+    /// group) and other entries take the mode bits, named entries are kept.
+    pub fn with_mode(&self, mode: u32) -> Acl {
+        let has_mask = self.perm(ACL_MASK).is_some();
+        let entries = self.entries.iter().map(|e| {
+            let perm = if e.tag == ACL_USER_OBJ {
+                (mode >> 6) & 7
+            } else if e.tag == ACL_MASK || (e.tag == ACL_GROUP_OBJ && !has_mask) {
+                (mode >> 3) & 7
+            } else if e.tag == ACL_OTHER {
+                mode & 7
+            } else {
+                e.perm as u32
+            };
+            AclEntry{tag:e.tag, perm:perm as u16, id:e.id}
+        }).collect();
+        Acl{entries:entries}
+    }
+
+    /// The POSIX.1e access check. Root is expected to be handled by the caller.
+    pub fn permits(&self, owner: u32, group: u32, uid: u32, gid: u32, mask: u32) -> bool {
+        let mask = mask as u16;
+        let acl_mask = self.perm(ACL_MASK).unwrap_or(7);
+        if uid == owner {
+            return self.perm(ACL_USER_OBJ).unwrap_or(0) & mask == mask;
+        }
+        if let Some(entry) = self.entries.iter().find(|e| e.tag == ACL_USER && e.id == uid) {
+            return entry.perm & acl_mask & mask == mask;
+        }
+        let groups:Vec<&AclEntry> = self.entries.iter().filter(|e| {
+            (e.tag == ACL_GROUP_OBJ && gid == group) || (e.tag == ACL_GROUP && e.id == gid)
+        }).collect();
+        if !groups.is_empty() {
+            return groups.iter().any(|e| e.perm & acl_mask & mask == mask);
+        }
+        self.perm(ACL_OTHER).unwrap_or(0) & mask == mask
+    }
+
+    /// The access ACL for a new inode made with mode in a directory with this
+    /// default ACL. The mode can only take permissions away, as in acl(5).
+    pub fn inherit(&self, mode: u32) -> Acl {
+        let has_mask = self.perm(ACL_MASK).is_some();
+        let entries = self.entries.iter().map(|e| {
+            let bits = if e.tag == ACL_USER_OBJ {
+                (mode >> 6) & 7
+            } else if e.tag == ACL_OTHER {
+                mode & 7
+            } else if e.tag == ACL_MASK || (e.tag == ACL_GROUP_OBJ && !has_mask) {
+                (mode >> 3) & 7
+            } else {
+                7
+            };
+            AclEntry{tag:e.tag, perm:e.perm & bits as u16, id:e.id}
+        }).collect();
+        Acl{entries:entries}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, AclEntry, ACL_USER_OBJ, ACL_USER, ACL_GROUP_OBJ, ACL_MASK, ACL_OTHER};
+
+    fn team_acl() -> Acl {
+        //user::rwx user:1001:rwx group::r-x mask::r-x other::---
+        Acl{entries:vec![
+            AclEntry{tag:ACL_USER_OBJ, perm:7, id:0xffffffff},
+            AclEntry{tag:ACL_USER, perm:7, id:1001},
+            AclEntry{tag:ACL_GROUP_OBJ, perm:5, id:0xffffffff},
+            AclEntry{tag:ACL_MASK, perm:5, id:0xffffffff},
+            AclEntry{tag:ACL_OTHER, perm:0, id:0xffffffff},
+        ]}
+    }
+
+    #[test]
+    fn round_trip() {
+        let acl = team_acl();
+        assert_eq!(Acl::parse(&acl.to_bytes()), Ok(acl));
+        assert!(Acl::parse(&[2, 0, 0, 0, 1]).is_err());
+        //named entries without a mask
+        let mut no_mask = team_acl();
+        no_mask.entries.remove(3);
+        assert!(Acl::parse(&no_mask.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn mask_limits_named_entries() {
+        let acl = team_acl();
+        assert!(acl.permits(1000, 100, 1000, 100, 2));
+        assert!(acl.permits(1000, 100, 1001, 200, 4));
+        assert!(!acl.permits(1000, 100, 1001, 200, 2));
+        assert!(!acl.permits(1000, 100, 1002, 200, 4));
+        assert_eq!(acl.mode(), 0o750);
+    }
+
+    #[test]
+    fn chmod_rewrites_mask() {
+        let acl = team_acl().with_mode(0o640);
+        assert_eq!(acl.mode(), 0o640);
+        //the named user keeps rwx, the mask now lets only r through
+        assert_eq!(acl.entries[1].perm, 7);
+        assert!(acl.permits(1000, 100, 1001, 200, 4));
+        assert!(!acl.permits(1000, 100, 1001, 200, 2));
+        //without a mask the owning group takes the group bits
+        let mut minimal = team_acl();
+        minimal.entries.remove(3);
+        minimal.entries.remove(1);
+        assert_eq!(minimal.with_mode(0o751).mode(), 0o751);
+    }
+
+    #[test]
+    fn inherit_masks_with_mode() {
+        let acl = team_acl().inherit(0o640);
+        assert_eq!(acl.mode(), 0o640);
+        assert!(acl.permits(1000, 100, 1001, 200, 4));
+        assert!(!acl.permits(1000, 100, 1001, 200, 1));
+    }
+}
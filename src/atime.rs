@@ -0,0 +1,217 @@
+//! Access time updates.
+//!
+//! What a read does to atime is picked at mount time, with the same names
+//! mount(8) uses. strictatime queues a write on every read. relatime, the
+//! default, only moves atime forward when it is older than mtime or ctime, or
+//! more than a day old, and then only in memory: new access times are
+//! collected per inode and written out together every ATIME_FLUSH seconds
+//! (or once ATIME_BATCH inodes are waiting, and on unmount), so each file
+//! costs at most one write per flush however often it is read. Requests
+//! check whether the flush is due, and so does the session heartbeat, which
+//! shares the collected times, so an idle mount writes them out as well. noatime never
+//! touches it. Access times aren't written to the change log, other clients
+//! see them once their attribute cache expires.
+//!
+//! Times set explicitly, by touch or utimensat, are written right away, and
+//! unlike the updates reads make they move ctime too.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use fuse::FileAttr;
+
+use cql_ffi::CassStatement;
+
+use consistency::OpClass;
+use time::{self, Timespec};
+
+use {CrustFS, INODE_PARTITIONS};
+
+//seconds a relatime update may wait before it is written
+pub static ATIME_FLUSH:i64 = 30;
+//inodes with an unwritten access time, past which they're written right away
+pub static ATIME_BATCH:usize = 1024;
+//relatime still updates an atime that is older than this, in seconds
+static RELATIME_MAX_AGE:i64 = 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtimePolicy {
+    NoAtime,
+    RelAtime,
+    StrictAtime,
+}
+
+impl AtimePolicy {
+    pub fn parse(name: &str) -> Option<AtimePolicy> {
+        match name {
+            "noatime" => Some(AtimePolicy::NoAtime),
+            "relatime" => Some(AtimePolicy::RelAtime),
+            "strictatime" => Some(AtimePolicy::StrictAtime),
+            _ => None,
+        }
+    }
+}
+
+/// Access times read but not written yet, by inode.
+pub struct Atimes {
+    pending:HashMap<u64, Timespec>,
+    //when pending was last written out
+    flushed:i64,
+}
+
+impl Atimes {
+    pub fn new() -> Atimes {
+        Atimes{pending:HashMap::new(), flushed:time::get_time().sec}
+    }
+
+    pub fn get(&self, ino: u64) -> Option<Timespec> {
+        self.pending.get(&ino).cloned()
+    }
+}
+
+pub fn new_atimes() -> Arc<Mutex<Atimes>> {
+    Arc::new(Mutex::new(Atimes::new()))
+}
+
+/// Would relatime update the atime of attr?
+fn relatime_due(attr: &FileAttr, now: Timespec) -> bool {
+    attr.atime <= attr.mtime || attr.atime <= attr.ctime || now.sec - attr.atime.sec >= RELATIME_MAX_AGE
+}
+
+/// Are the times setattr was given all "now", as utimes(NULL) and UTIME_NOW
+/// ask for? The kernel sends those as its own clock reading, not as a flag.
+pub fn only_now(atime: Option<Timespec>, mtime: Option<Timespec>, now: Timespec) -> bool {
+    [atime, mtime].iter().all(|time| time.map(|time| (now.sec - time.sec).abs() <= 1).unwrap_or(true))
+}
+
+impl CrustFS {
+    fn queue_atime(&mut self, ino: u64, atime: Timespec) {
+        let statement = CassStatement::new(self.cmds.update_atime, 4);
+        statement.bind_int64(0, atime.sec).unwrap();
+        statement.bind_int32(1, atime.nsec).unwrap();
+        statement.bind_int64(2, (ino % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(3, ino as i64).unwrap();
+        self.queue_write(ino, OpClass::MetaWrite, statement);
+    }
+
+    /// Note that ino was just read, or listed if it is a directory.
+    pub fn touch_atime(&mut self, attr: &FileAttr) {
+        let now = time::get_time();
+        match self.atime_policy {
+            AtimePolicy::NoAtime => return,
+            AtimePolicy::StrictAtime => self.queue_atime(attr.ino, now),
+            AtimePolicy::RelAtime => {
+                //attr already reflects what is waiting in atimes
+                if !relatime_due(attr, now) {
+                    return;
+                }
+                self.atimes.lock().unwrap().pending.insert(attr.ino, now);
+            },
+        }
+        //getattr shouldn't have to wait for the write to see it
+        if let Some(mut cached) = self.cache.attr(attr.ino) {
+            cached.atime = now;
+            self.cache.insert_attr(&cached);
+        }
+        self.flush_expired_atimes();
+    }
+
+    /// Write out the waiting access times if they have waited long enough.
+    pub fn flush_expired_atimes(&mut self) {
+        let due = {
+            let atimes = self.atimes.lock().unwrap();
+            atimes.pending.len() >= ATIME_BATCH || time::get_time().sec - atimes.flushed >= ATIME_FLUSH
+        };
+        if due {
+            self.flush_atimes();
+        }
+    }
+
+    /// Set atime and/or mtime for setattr. ctime becomes now.
+    pub fn set_times(&mut self, ino: u64, atime: Option<Timespec>, mtime: Option<Timespec>) {
+        //a buffered write would bring its own mtime along later
+        self.flush_buffer(ino);
+        if let Some(atime) = atime {
+            self.atimes.lock().unwrap().pending.remove(&ino);
+            self.queue_atime(ino, atime);
+        }
+        let now = time::get_time();
+        let statement = match mtime {
+            Some(mtime) => {
+                let statement = CassStatement::new(self.cmds.update_mtime, 6);
+                statement.bind_int64(0, mtime.sec).unwrap();
+                statement.bind_int32(1, mtime.nsec).unwrap();
+                statement
+            },
+            None if atime.is_some() => CassStatement::new(self.cmds.update_ctime, 4),
+            None => return,
+        };
+        let first = if mtime.is_some() {2} else {0};
+        statement.bind_int64(first, now.sec).unwrap();
+        statement.bind_int32(first + 1, now.nsec).unwrap();
+        statement.bind_int64(first + 2, (ino % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(first + 3, ino as i64).unwrap();
+        self.queue_write(ino, OpClass::MetaWrite, statement);
+        self.note_rstats(ino, 0, 0, 0, now);
+        self.cache.invalidate_attr(ino);
+        self.log_change(ino, None);
+    }
+
+    pub fn flush_atimes(&mut self) {
+        let pending:Vec<(u64, Timespec)> = {
+            let mut atimes = self.atimes.lock().unwrap();
+            atimes.flushed = time::get_time().sec;
+            atimes.pending.drain().collect()
+        };
+        if !pending.is_empty() {
+            debug!("flush_atimes: writing {} access times", pending.len());
+        }
+        for (ino, atime) in pending.into_iter() {
+            self.queue_atime(ino, atime);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fuse::{FileAttr, FileType};
+    use time::Timespec;
+
+    use super::{AtimePolicy, only_now, relatime_due, RELATIME_MAX_AGE};
+
+    fn attr(atime: i64, mtime: i64, ctime: i64) -> FileAttr {
+        FileAttr{ino:2, size:0, blocks:0, atime:Timespec::new(atime, 0), mtime:Timespec::new(mtime, 0),
+            ctime:Timespec::new(ctime, 0), crtime:Timespec::new(0, 0), kind:FileType::RegularFile,
+            perm:0o644, nlink:1, uid:0, gid:0, rdev:0, flags:0}
+    }
+
+    #[test]
+    fn policy_names() {
+        assert_eq!(AtimePolicy::parse("noatime"), Some(AtimePolicy::NoAtime));
+        assert_eq!(AtimePolicy::parse("relatime"), Some(AtimePolicy::RelAtime));
+        assert_eq!(AtimePolicy::parse("strictatime"), Some(AtimePolicy::StrictAtime));
+        assert_eq!(AtimePolicy::parse("atime"), None);
+    }
+
+    #[test]
+    fn relatime_updates() {
+        let now = Timespec::new(1000000, 0);
+        //read since the last change, and recently: nothing to do
+        assert!(!relatime_due(&attr(999000, 998000, 998000), now));
+        //changed since the last read
+        assert!(relatime_due(&attr(998000, 999000, 998000), now));
+        assert!(relatime_due(&attr(998000, 998000, 999000), now));
+        //read long ago
+        assert!(relatime_due(&attr(now.sec - RELATIME_MAX_AGE, 0, 0), now));
+        assert!(!relatime_due(&attr(now.sec - RELATIME_MAX_AGE + 1, 0, 0), now));
+    }
+
+    #[test]
+    fn touch_versus_explicit_times() {
+        let now = Timespec::new(1000000, 500);
+        assert!(only_now(Some(now), Some(now), now));
+        assert!(only_now(None, Some(Timespec::new(999999, 900)), now));
+        assert!(!only_now(Some(Timespec::new(0, 0)), Some(now), now));
+        assert!(!only_now(None, Some(Timespec::new(2000000, 0)), now));
+    }
+}
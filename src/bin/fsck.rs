@@ -0,0 +1,519 @@
+#![feature(libc)]
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+extern crate cql_ffi;
+extern crate crustfs;
+
+use cql_ffi::CassCluster;
+use cql_ffi::CassSession;
+use cql_ffi::CassStatement;
+use crustfs::{CrustFS, InodeRow, INODE_PARTITIONS, ROOT_INODE, LOST_FOUND_INODE, BLOCK_SIZE};
+use crustfs::data::CHUNK_SIZE;
+use time::Timespec;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::process;
+use std::u64;
+
+//exit codes follow fsck(8)
+static FSCK_OK:i32 = 0;
+static FSCK_CORRECTED:i32 = 1;
+static FSCK_UNCORRECTED:i32 = 4;
+
+fn usage() -> ! {
+    println!("usage: fsck.crustfs [--repair] [--grace secs]");
+    process::exit(16);
+}
+
+/// The unreachable inodes to hang off lost+found so that all of them can be
+/// reached again: first those no unreachable directory lists, then, for
+/// directories that only list each other in a cycle, one of the cycle.
+fn orphan_roots(unreachable: &HashSet<u64>, children: &HashMap<u64, Vec<u64>>) -> Vec<u64> {
+    let mut lister = HashMap::new();
+    for dir in unreachable.iter() {
+        for child in children.get(dir).map(|c| &c[..]).unwrap_or(&[]).iter() {
+            lister.entry(*child).or_insert(*dir);
+        }
+    }
+    let mut candidates:Vec<u64> = unreachable.iter().cloned().collect();
+    candidates.sort();
+    let (tops, rest):(Vec<u64>, Vec<u64>) = candidates.into_iter().partition(|ino| !lister.contains_key(ino));
+    let mut roots = Vec::new();
+    let mut covered = HashSet::new();
+    for candidate in tops.into_iter().chain(rest.into_iter()) {
+        if covered.contains(&candidate) {
+            continue;
+        }
+        //anything still uncovered hangs below a cycle; climb into it
+        let mut ino = candidate;
+        let mut seen = HashSet::new();
+        while seen.insert(ino) {
+            match lister.get(&ino) {
+                Some(dir) => ino = *dir,
+                None => break,
+            }
+        }
+        roots.push(ino);
+        covered.insert(ino);
+        let mut queue = vec![ino];
+        while let Some(dir) = queue.pop() {
+            for child in children.get(&dir).map(|c| &c[..]).unwrap_or(&[]).iter() {
+                if unreachable.contains(child) && covered.insert(*child) {
+                    queue.push(*child);
+                }
+            }
+        }
+    }
+    roots
+}
+
+struct Fsck<'a> {
+    crustfs:&'a CrustFS,
+    inodes:HashMap<u64,InodeRow>,
+    repair:bool,
+    //placeholders reserved since then may still be filled in by a create
+    cutoff:i64,
+    found:u32,
+    fixed:u32,
+}
+
+impl<'a> Fsck<'a> {
+    fn problem(&mut self, msg: String) {
+        println!("{}", msg);
+        self.found += 1;
+    }
+
+    fn run(&mut self, statement: CassStatement) {
+        match self.crustfs.execute_statement(statement).wait() {
+            Ok(_) => self.fixed += 1,
+            Err(err) => println!("  repair failed: {:?}", err),
+        }
+    }
+
+    /// Entries of dir were repaired; its times move as if a mount had done it.
+    fn dir_changed(&mut self, dir: u64) {
+        if let Err(err) = self.crustfs.touch_dir(dir) {
+            println!("  unable to update the times of directory {}: {:?}", dir, err);
+        }
+    }
+
+    fn is_live(&self, ino: u64) -> bool {
+        self.inodes.get(&ino).map(|row| !row.is_placeholder()).unwrap_or(false)
+    }
+
+    fn load(&mut self) {
+        for partition in 0..INODE_PARTITIONS {
+            match self.crustfs.select_partition(partition) {
+                Err(err) => {
+                    println!("unable to read inode partition {}: {:?}", partition, err);
+                    process::exit(8);
+                },
+                Ok(rows) => for row in rows.into_iter() {
+                    //inode 0 is the null inode that anchors allocation in partition 0
+                    if row.inode != 0 {
+                        self.inodes.insert(row.inode, row);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Entries naming an inode that doesn't exist, or was never filled in by create.
+    fn check_dirents(&mut self) {
+        let mut dangling = Vec::new();
+        for row in self.inodes.values() {
+            for (name, child) in row.dir_contents.iter() {
+                if !self.is_live(*child) {
+                    dangling.push((row.part_id, row.inode, name.clone(), *child));
+                }
+            }
+        }
+        for (part_id, ino, name, child) in dangling.into_iter() {
+            self.problem(format!("dangling dirent {:?} in {} points at missing inode {}", name, ino, child));
+            self.inodes.get_mut(&ino).unwrap().dir_contents.remove(&name);
+            if self.repair {
+                let statement = CassStatement::new(self.crustfs.cmds.remove_inode_from_parent, 3);
+                statement.bind_string(0, name.as_str()).unwrap();
+                statement.bind_int64(1, part_id as i64).unwrap();
+                statement.bind_int64(2, ino as i64).unwrap();
+                self.run(statement);
+                self.dir_changed(ino);
+            }
+        }
+    }
+
+    /// Placeholders older than the grace period that no create filled in.
+    /// Like crustfs-gc, the delete only goes through if that is still so.
+    fn check_placeholders(&mut self) {
+        let cutoff = self.cutoff;
+        let placeholders:Vec<(u64,u64)> = self.inodes.values()
+            .filter(|row| row.is_placeholder() && row.crtime.sec < cutoff)
+            .map(|row| (row.part_id, row.inode)).collect();
+        for (part_id, ino) in placeholders.into_iter() {
+            self.problem(format!("orphaned placeholder inode {}", ino));
+            if self.repair {
+                let statement = CassStatement::new(self.crustfs.cmds.delete_placeholder_inode, 2);
+                statement.bind_int64(0, part_id as i64).unwrap();
+                statement.bind_int64(1, ino as i64).unwrap();
+                match self.crustfs.execute_lwt(statement) {
+                    Ok(true) => self.fixed += 1,
+                    Ok(false) => println!("  inode {} was filled in meanwhile, left alone", ino),
+                    Err(err) => println!("  repair failed: {:?}", err),
+                }
+            }
+        }
+    }
+
+    /// Walk the tree from the root and hang anything we can't reach off lost+found.
+    fn check_reachability(&mut self) {
+        let mut reachable = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(ROOT_INODE);
+        reachable.insert(ROOT_INODE);
+        while let Some(ino) = queue.pop_front() {
+            for child in self.inodes[&ino].dir_contents.values() {
+                if reachable.insert(*child) {
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        //only report the top of each unreachable subtree; its children come along with it
+        let unreachable:HashSet<u64> = self.inodes.values()
+            .filter(|row| !row.is_placeholder() && !reachable.contains(&row.inode))
+            .map(|row| row.inode).collect();
+        let children:HashMap<u64, Vec<u64>> = unreachable.iter()
+            .map(|ino| (*ino, self.inodes[ino].dir_contents.values().cloned().collect())).collect();
+        let roots = orphan_roots(&unreachable, &children);
+
+        let have_lost_found = self.is_live(LOST_FOUND_INODE) && reachable.contains(&LOST_FOUND_INODE);
+        for ino in roots.into_iter() {
+            //a root in a cycle is still listed by the directory that closes it
+            let mut listing = Vec::new();
+            for dir in unreachable.iter() {
+                let row = &self.inodes[dir];
+                for (name, _) in row.dir_contents.iter().filter(|&(_, child)| *child == ino) {
+                    listing.push((row.part_id, *dir, name.clone()));
+                }
+            }
+            if listing.is_empty() {
+                self.problem(format!("unreachable subtree rooted at inode {}", ino));
+            } else {
+                self.problem(format!("unreachable cycle of directories through inode {}", ino));
+            }
+            if !have_lost_found {
+                if self.repair {
+                    println!("  no lost+found, leaving inode {} alone", ino);
+                }
+                continue;
+            }
+            for (part_id, dir, name) in listing.into_iter() {
+                self.inodes.get_mut(&dir).unwrap().dir_contents.remove(&name);
+                if self.repair {
+                    let statement = CassStatement::new(self.crustfs.cmds.remove_inode_from_parent, 3);
+                    statement.bind_string(0, name.as_str()).unwrap();
+                    statement.bind_int64(1, part_id as i64).unwrap();
+                    statement.bind_int64(2, dir as i64).unwrap();
+                    self.run(statement);
+                    self.dir_changed(dir);
+                }
+            }
+            let name = format!("#{}", ino);
+            self.inodes.get_mut(&LOST_FOUND_INODE).unwrap().dir_contents.insert(name.clone(), ino);
+            self.inodes.get_mut(&ino).unwrap().parent_inode = Some(LOST_FOUND_INODE);
+            if self.repair {
+                let statement = CassStatement::new(self.crustfs.cmds.add_inode_to_parent, 4);
+                statement.bind_string(0, name.as_str()).unwrap();
+                statement.bind_int64(1, ino as i64).unwrap();
+                statement.bind_int64(2, (LOST_FOUND_INODE % INODE_PARTITIONS) as i64).unwrap();
+                statement.bind_int64(3, LOST_FOUND_INODE as i64).unwrap();
+                self.run(statement);
+                self.dir_changed(LOST_FOUND_INODE);
+                let statement = CassStatement::new(self.crustfs.cmds.update_parent_inode, 3);
+                statement.bind_int64(0, LOST_FOUND_INODE as i64).unwrap();
+                statement.bind_int64(1, (ino % INODE_PARTITIONS) as i64).unwrap();
+                statement.bind_int64(2, ino as i64).unwrap();
+                self.run(statement);
+            }
+        }
+    }
+
+    /// A directory's ".." must point at the directory that lists it.
+    fn check_parents(&mut self) {
+        let mut wrong = Vec::new();
+        for row in self.inodes.values().filter(|row| row.is_dir()) {
+            for child in row.dir_contents.values() {
+                let child_row = &self.inodes[child];
+                if child_row.is_dir() && child_row.parent_inode != Some(row.inode) {
+                    wrong.push((*child, row.inode));
+                }
+            }
+        }
+        for (ino, parent) in wrong.into_iter() {
+            self.problem(format!("directory {} has parent {:?}, but is listed in {}",
+                ino, self.inodes[&ino].parent_inode, parent));
+            if self.repair {
+                let statement = CassStatement::new(self.crustfs.cmds.update_parent_inode, 3);
+                statement.bind_int64(0, parent as i64).unwrap();
+                statement.bind_int64(1, (ino % INODE_PARTITIONS) as i64).unwrap();
+                statement.bind_int64(2, ino as i64).unwrap();
+                self.run(statement);
+            }
+        }
+    }
+
+    fn check_nlink(&mut self) {
+        let mut expected:HashMap<u64,u32> = HashMap::new();
+        for row in self.inodes.values() {
+            if row.is_dir() {
+                //"." plus the ".." of each subdirectory; the entry in the parent is counted below
+                let subdirs = row.dir_contents.values().filter(|c| self.inodes[c].is_dir()).count();
+                *expected.entry(row.inode).or_insert(0) += 1 + subdirs as u32;
+            }
+            for child in row.dir_contents.values() {
+                *expected.entry(*child).or_insert(0) += 1;
+            }
+        }
+        //the root has no entry in a parent, but its ".." points at itself
+        *expected.entry(ROOT_INODE).or_insert(0) += 1;
+
+        let mut wrong = Vec::new();
+        for row in self.inodes.values().filter(|row| !row.is_placeholder()) {
+            let nlink = *expected.get(&row.inode).unwrap_or(&0);
+            if nlink != row.nlink {
+                wrong.push((row.part_id, row.inode, row.nlink, nlink));
+            }
+        }
+        for (part_id, ino, found, nlink) in wrong.into_iter() {
+            self.problem(format!("inode {} has nlink {}, should be {}", ino, found, nlink));
+            if self.repair {
+                let statement = CassStatement::new(self.crustfs.cmds.update_nlink, 3);
+                statement.bind_int32(0, nlink as i32).unwrap();
+                statement.bind_int64(1, part_id as i64).unwrap();
+                statement.bind_int64(2, ino as i64).unwrap();
+                self.run(statement);
+            }
+        }
+    }
+
+    /// Blocks count the chunks a file has in the chunk map (or its bytes, if
+    /// it is inline), and no chunk may lie past the end of its file.
+    fn check_chunks(&mut self) {
+        let files:Vec<(u64,u64,u64,u64,bool)> = self.inodes.values()
+            .filter(|row| !row.is_placeholder() && !row.is_dir())
+            .map(|row| (row.part_id, row.inode, row.size, row.blocks, row.inline_data.is_some())).collect();
+        for (part_id, ino, size, blocks, inline) in files.into_iter() {
+            let chunks = match self.crustfs.chunk_map(ino, 0, u64::MAX) {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    println!("unable to read chunk map of inode {}: {:?}", ino, err);
+                    continue;
+                }
+            };
+            //an inline file shouldn't have any
+            let (stray, kept):(Vec<u64>, Vec<u64>) = chunks.into_iter()
+                .partition(|chunk| inline || chunk * CHUNK_SIZE >= size);
+            for chunk in stray.into_iter() {
+                self.problem(format!("inode {} has chunk {} past its end", ino, chunk));
+                if self.repair {
+                    for cmd in [self.crustfs.cmds.delete_chunk, self.crustfs.cmds.delete_chunk_map].iter() {
+                        let statement = CassStatement::new(*cmd, 2);
+                        statement.bind_int64(0, ino as i64).unwrap();
+                        statement.bind_int64(1, chunk as i64).unwrap();
+                        self.run(statement);
+                    }
+                }
+            }
+            let expected = if inline {
+                (size + BLOCK_SIZE - 1) / BLOCK_SIZE
+            } else {
+                kept.len() as u64 * (CHUNK_SIZE / BLOCK_SIZE)
+            };
+            if blocks != expected {
+                self.problem(format!("inode {} has {} blocks, should be {}", ino, blocks, expected));
+                if self.repair {
+                    let statement = CassStatement::new(self.crustfs.cmds.update_blocks, 3);
+                    statement.bind_int64(0, expected as i64).unwrap();
+                    statement.bind_int64(1, part_id as i64).unwrap();
+                    statement.bind_int64(2, ino as i64).unwrap();
+                    self.run(statement);
+                }
+                //check_usage adds up what the blocks should be
+                self.inodes.get_mut(&ino).unwrap().blocks = expected;
+            }
+        }
+    }
+
+    /// Every directory's subtree counters and rctime, worked out from the
+    /// directory entries. An rctime may only be repaired upwards.
+    fn check_rstats(&mut self) {
+        let mut listed_in = HashMap::new();
+        for row in self.inodes.values().filter(|row| row.is_dir()) {
+            for child in row.dir_contents.values() {
+                listed_in.insert(*child, row.inode);
+            }
+        }
+        let mut expected:HashMap<u64,(i64,i64,i64,Timespec)> = HashMap::new();
+        for row in self.inodes.values().filter(|row| !row.is_placeholder()) {
+            let (bytes, files, subdirs) = if row.is_dir() {(0, 0, 1)} else {(row.size as i64, 1, 0)};
+            if row.is_dir() {
+                let stats = expected.entry(row.inode).or_insert((0, 0, 0, row.ctime));
+                stats.3 = cmp::max(stats.3, row.ctime);
+            }
+            let mut seen = HashSet::new();
+            let mut dir = listed_in.get(&row.inode).cloned();
+            while let Some(ancestor) = dir {
+                if !seen.insert(ancestor) {
+                    break;
+                }
+                let stats = expected.entry(ancestor).or_insert((0, 0, 0, row.ctime));
+                stats.0 += bytes;
+                stats.1 += files;
+                stats.2 += subdirs;
+                stats.3 = cmp::max(stats.3, row.ctime);
+                dir = listed_in.get(&ancestor).cloned();
+            }
+        }
+        for (dir, (bytes, files, subdirs, ctime)) in expected.into_iter() {
+            let stats = match self.crustfs.rstats(dir) {
+                Ok(stats) => stats,
+                Err(err) => {
+                    println!("unable to read statistics of inode {}: {:?}", dir, err);
+                    continue;
+                }
+            };
+            if (stats.bytes as i64, stats.files as i64, stats.subdirs as i64) != (bytes, files, subdirs) {
+                self.problem(format!("directory {} counts {} bytes, {} files and {} subdirs below it, found {}, {} and {}",
+                    dir, stats.bytes, stats.files, stats.subdirs, bytes, files, subdirs));
+                if self.repair {
+                    match self.crustfs.charge_rstats(dir, bytes - stats.bytes as i64, files - stats.files as i64,
+                                                     subdirs - stats.subdirs as i64) {
+                        Ok(_) => self.fixed += 1,
+                        Err(err) => println!("  repair failed: {:?}", err),
+                    }
+                }
+            }
+            if stats.ctime.map(|rctime| rctime < ctime).unwrap_or(true) {
+                self.problem(format!("directory {} has rctime {:?}, but something below it changed at {:?}",
+                    dir, stats.ctime, ctime));
+                if self.repair {
+                    match self.crustfs.raise_rctime(dir, ctime) {
+                        Ok(_) => self.fixed += 1,
+                        Err(err) => println!("  repair failed: {:?}", err),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Byte usage is the blocks of every file. Counters only support
+    /// increments, so repair adds whatever is missing.
+    fn check_usage(&mut self) {
+        let live = self.inodes.values().filter(|row| !row.is_placeholder());
+        let (bytes, inodes) = live.fold((0, 0), |(bytes, inodes), row| {
+            (if row.is_dir() {bytes} else {bytes + row.blocks * BLOCK_SIZE}, inodes + 1)
+        });
+        match self.crustfs.usage() {
+            Err(err) => println!("unable to read usage counters: {:?}", err),
+            Ok((used_bytes, used_inodes)) => if (used_bytes, used_inodes) != (bytes, inodes) {
+                self.problem(format!("usage counters report {} bytes in {} inodes, found {} bytes in {} inodes",
+                    used_bytes, used_inodes, bytes, inodes));
+                if self.repair {
+                    match self.crustfs.update_usage(bytes as i64 - used_bytes as i64, inodes as i64 - used_inodes as i64) {
+                        Ok(_) => self.fixed += 1,
+                        Err(err) => println!("  repair failed: {:?}", err),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut repair = false;
+    //the same default as crustfs-gc
+    let mut grace = 3600;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            "--grace" => grace = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            _ => usage(),
+        }
+    }
+
+    //FIXME contact points should be configurable
+    let contact_points = "127.0.0.1";
+    let cluster = CassCluster::new()
+          .set_contact_points(contact_points).unwrap();
+    let session = CassSession::new();
+    match session.connect(&cluster).wait() {
+    Err(fail) => {
+        println!("fail: {}",fail);
+        process::exit(8);
+    },
+    Ok(session) => {
+      let crustfs = CrustFS::build(session);
+      if let Err(err) = crustfs.upgrade_schema() {
+          println!("unable to bring the schema up to date: {:?}", err);
+          process::exit(8);
+      }
+      let mut fsck = Fsck{crustfs:&crustfs, inodes:HashMap::new(), repair:repair,
+          cutoff:time::get_time().sec - grace, found:0, fixed:0};
+      fsck.load();
+      if !fsck.is_live(ROOT_INODE) {
+          println!("root inode is missing, run mkcrustfs");
+          process::exit(FSCK_UNCORRECTED);
+      }
+      println!("checking {} inodes", fsck.inodes.len());
+      fsck.check_placeholders();
+      fsck.check_dirents();
+      fsck.check_reachability();
+      fsck.check_parents();
+      fsck.check_nlink();
+      fsck.check_chunks();
+      fsck.check_rstats();
+      fsck.check_usage();
+      println!("{} problems found, {} repairs made", fsck.found, fsck.fixed);
+      process::exit(if fsck.found == 0 {FSCK_OK} else if repair {FSCK_CORRECTED} else {FSCK_UNCORRECTED});
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::orphan_roots;
+
+    fn tree(edges: &[(u64, &[u64])]) -> (HashSet<u64>, HashMap<u64, Vec<u64>>) {
+        let mut unreachable = HashSet::new();
+        let mut children = HashMap::new();
+        for &(dir, listed) in edges.iter() {
+            unreachable.insert(dir);
+            unreachable.extend(listed.iter().cloned());
+            children.insert(dir, listed.to_vec());
+        }
+        (unreachable, children)
+    }
+
+    #[test]
+    fn subtree_tops() {
+        let (unreachable, children) = tree(&[(10, &[11, 12]), (12, &[13]), (20, &[])]);
+        assert_eq!(orphan_roots(&unreachable, &children), vec![10, 20]);
+    }
+
+    #[test]
+    fn cycles_get_a_root() {
+        //30 and 31 list each other, 32 hangs below them
+        let (unreachable, children) = tree(&[(10, &[11]), (30, &[31]), (31, &[30, 32])]);
+        let roots = orphan_roots(&unreachable, &children);
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0], 10);
+        assert!(roots[1] == 30 || roots[1] == 31);
+    }
+}
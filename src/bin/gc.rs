@@ -0,0 +1,168 @@
+#![feature(libc)]
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+extern crate cql_ffi;
+extern crate crustfs;
+
+use cql_ffi::CassCluster;
+use cql_ffi::CassSession;
+use cql_ffi::{CassStatement, CassError};
+use crustfs::{CrustFS, InodeRow, INODE_PARTITIONS, ROOT_INODE};
+
+use std::collections::HashSet;
+use std::env;
+use std::i64;
+use std::process;
+use std::thread;
+
+struct Options {
+    grace:i64,      //seconds an inode must have existed before it is reaped
+    rate:u32,       //deletes per second (1-1000), to keep from flooding the cluster with tombstones
+    interval:u32,   //seconds between passes, 0 for a single pass
+    dry_run:bool,
+}
+
+fn usage() -> ! {
+    println!("usage: crustfs-gc [--grace secs] [--rate deletes/sec] [--interval secs] [--dry-run]");
+    println!("reaps inodes a create reserved or filled in but never linked, with their xattrs and chunks.");
+    println!("files that lose their last link will need reaping too once unlink exists; nothing makes them yet.");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options{grace:3600, rate:100, interval:0, dry_run:false};
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--grace" => opts.grace = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            "--rate" => opts.rate = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            "--interval" => opts.interval = args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage()),
+            "--dry-run" => opts.dry_run = true,
+            _ => usage(),
+        }
+    }
+    //the pause between deletes is whole milliseconds
+    if opts.rate == 0 || opts.rate > 1000 { usage() }
+    opts
+}
+
+/// Wait between deletes so that at most opts.rate go out per second.
+fn pause(opts: &Options) {
+    thread::sleep_ms(1000 / opts.rate);
+}
+
+/// An inode that create filled in but never managed to link: nothing lists
+/// it, and it is still empty. Anything unreachable with contents is for
+/// fsck.crustfs --repair to move to lost+found instead.
+fn failed_create(row: &InodeRow, referenced: &HashSet<u64>) -> bool {
+    //inode 0 is the null inode mkcrustfs writes, and nothing links the root
+    row.inode != 0 && row.inode != ROOT_INODE && row.parent_inode.is_some()
+        && !referenced.contains(&row.inode) && row.size == 0 && row.dir_contents.is_empty()
+}
+
+/// Delete what an unlinked inode may have stored besides its row: xattrs,
+/// chunks and the chunk map. Returns how many deletes that took.
+fn delete_contents(crustfs: &CrustFS, opts: &Options, ino: u64) -> Result<u32, CassError> {
+    let statement = CassStatement::new(crustfs.cmds.delete_xattrs, 1);
+    statement.bind_int64(0, ino as i64).unwrap();
+    try!(crustfs.execute_statement(statement).wait());
+    pause(opts);
+    let chunks = try!(crustfs.chunk_map(ino, 0, i64::MAX as u64));
+    for chunk in chunks.iter() {
+        let statement = CassStatement::new(crustfs.cmds.delete_chunk, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, *chunk as i64).unwrap();
+        try!(crustfs.execute_statement(statement).wait());
+        pause(opts);
+    }
+    let statement = CassStatement::new(crustfs.cmds.delete_chunk_maps, 1);
+    statement.bind_int64(0, ino as i64).unwrap();
+    try!(crustfs.execute_statement(statement).wait());
+    pause(opts);
+    Ok(2 + chunks.len() as u32)
+}
+
+/// One pass over every inode partition. Two kinds of inode are reaped:
+/// placeholders reserved by allocate_inode that never got a parent, and
+/// inodes create filled in but couldn't link (see failed_create), along with
+/// their xattrs and chunks. Neither was ever charged to usage or quotas.
+/// Every delete is conditional on the row being as we found it, so a create
+/// that finishes while we are running wins the race.
+fn reap(crustfs: &CrustFS, opts: &Options) -> u32 {
+    let cutoff = time::get_time().sec as i64 - opts.grace;
+    let mut rows = Vec::new();
+    for partition in 0..INODE_PARTITIONS {
+        match crustfs.select_partition(partition) {
+            Ok(partition_rows) => rows.extend(partition_rows.into_iter()),
+            Err(err) => {
+                //an entry in an unread partition could be the only link to anything
+                println!("unable to read inode partition {}: {:?}, giving up on this pass", partition, err);
+                return 0;
+            }
+        }
+    }
+    let referenced:HashSet<u64> = rows.iter().flat_map(|row| row.dir_contents.values().cloned()).collect();
+    let mut reaped = 0;
+    for row in rows.iter().filter(|row| row.crtime.sec < cutoff) {
+        let statement = if row.is_placeholder() {
+            println!("reaping placeholder inode {} reserved at {}", row.inode, row.crtime.sec);
+            if opts.dry_run {
+                continue;
+            }
+            CassStatement::new(crustfs.cmds.delete_placeholder_inode, 2)
+        } else if failed_create(row, &referenced) {
+            println!("reaping unlinked inode {} created at {}", row.inode, row.crtime.sec);
+            if opts.dry_run {
+                continue;
+            }
+            if let Err(err) = delete_contents(crustfs, opts, row.inode) {
+                println!("unable to delete the contents of inode {}: {:?}", row.inode, err);
+                continue;
+            }
+            let statement = CassStatement::new(crustfs.cmds.delete_unlinked_inode, 3);
+            statement.bind_int64(2, row.parent_inode.unwrap() as i64).unwrap();
+            statement
+        } else {
+            continue;
+        };
+        statement.bind_int64(0, row.part_id as i64).unwrap();
+        statement.bind_int64(1, row.inode as i64).unwrap();
+        match crustfs.execute_lwt(statement) {
+            Ok(true) => reaped += 1,
+            Ok(false) => println!("inode {} changed meanwhile, left alone", row.inode),
+            Err(err) => println!("unable to delete inode {}: {:?}", row.inode, err),
+        }
+        pause(opts);
+    }
+    reaped
+}
+
+fn main() {
+    let opts = parse_args();
+
+    //FIXME contact points should be configurable
+    let contact_points = "127.0.0.1";
+    let cluster = CassCluster::new()
+          .set_contact_points(contact_points).unwrap();
+    let session = CassSession::new();
+    match session.connect(&cluster).wait() {
+    Err(fail) => println!("fail: {}",fail),
+    Ok(session) => {
+      let crustfs = CrustFS::build(session);
+      if let Err(err) = crustfs.upgrade_schema() {
+          println!("unable to bring the schema up to date: {:?}", err);
+          process::exit(1);
+      }
+      loop {
+          let reaped = reap(&crustfs, &opts);
+          println!("reaped {} orphaned inodes", reaped);
+          if opts.interval == 0 {
+              break;
+          }
+          thread::sleep_ms(opts.interval * 1000);
+      }
+    }
+  }
+}
@@ -9,9 +9,93 @@ extern crate crustfs;
 use cql_ffi::CassCluster;
 use cql_ffi::CassSession;
 use cql_ffi::CassStatement;
-use crustfs::CrustFS;
+use crustfs::{CrustFS, INODE_PARTITIONS, ROOT_INODE, LOST_FOUND_INODE, parse_size};
+use crustfs::data::CHUNK_SIZE;
+
+use std::env;
+use std::process;
+
+struct Options {
+    root_uid:u32,
+    root_gid:u32,
+    root_mode:u32,
+    lost_found:bool,
+    capacity:Option<u64>,
+    max_inodes:Option<u64>,
+    inline_max:Option<u64>,
+}
+
+fn usage() -> ! {
+    println!("usage: mkcrustfs [--root-owner uid:gid] [--root-mode 0755] [--no-lost-found]");
+    println!("                 [--capacity size[K|M|G|T]] [--max-inodes count]");
+    println!("                 [--inline-max size[K]]");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options{root_uid:0, root_gid:0, root_mode:0o755, lost_found:true,
+        capacity:None, max_inodes:None, inline_max:None};
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root-owner" => {
+                let owner = args.next().unwrap_or_else(|| usage());
+                let ids:Vec<&str> = owner.split(':').collect();
+                if ids.len() != 2 { usage() }
+                opts.root_uid = ids[0].parse().unwrap_or_else(|_| usage());
+                opts.root_gid = ids[1].parse().unwrap_or_else(|_| usage());
+            },
+            "--root-mode" => {
+                let mode = args.next().unwrap_or_else(|| usage());
+                opts.root_mode = u32::from_str_radix(mode.as_str(), 8).unwrap_or_else(|_| usage());
+                if opts.root_mode > 0o7777 { usage() }
+            },
+            "--no-lost-found" => opts.lost_found = false,
+            "--capacity" => opts.capacity = Some(args.next().and_then(|v| parse_size(v.as_str())).unwrap_or_else(|| usage())),
+            "--max-inodes" => opts.max_inodes = Some(args.next().and_then(|v| v.parse().ok()).unwrap_or_else(|| usage())),
+            "--inline-max" => {
+                let inline_max = args.next().and_then(|v| parse_size(v.as_str())).unwrap_or_else(|| usage());
+                //an inline file has to fit in the first chunk when it is promoted
+                if inline_max > CHUNK_SIZE { usage() }
+                opts.inline_max = Some(inline_max);
+            },
+            _ => usage(),
+        }
+    }
+    opts
+}
+
+//parent_inode of the root points at the root itself, so ".." never leaves the fs
+fn insert_dir(crustfs: &CrustFS, inode: u64, parent: u64, perm: u32, nlink: u32, uid: u32, gid: u32) {
+    let statement = CassStatement::new(&crustfs.cmds.insert_dir_inode,15);
+    let now = time::get_time();
+    let seconds = now.sec as i64;
+    statement.bind_int64(0, (inode % INODE_PARTITIONS) as i64).unwrap();
+    statement.bind_int64(1, inode as i64).unwrap();
+    statement.bind_int64(2, parent as i64).unwrap();
+    statement.bind_int64(3, seconds).unwrap();
+    statement.bind_int64(4, seconds).unwrap();
+    statement.bind_int64(5, seconds).unwrap();
+    statement.bind_int64(6, seconds).unwrap();
+    statement.bind_int32(7, perm as i32).unwrap();
+    statement.bind_int32(8, nlink as i32).unwrap();
+    statement.bind_int32(9, uid as i32).unwrap();
+    statement.bind_int32(10, gid as i32).unwrap();
+    for i in 11..15 {
+        statement.bind_int32(i, now.nsec).unwrap();
+    }
+    assert!(crustfs.execute_statement(statement).wait().is_ok());
+}
+
+fn insert_metadata(crustfs: &CrustFS, key: &str, value: u64) {
+    let statement = CassStatement::new(&crustfs.cmds.insert_fs_metadata,2);
+    statement.bind_string(0, key).unwrap();
+    statement.bind_string(1, value.to_string().as_str()).unwrap();
+    assert!(crustfs.execute_statement(statement).wait().is_ok());
+}
 
 fn main() {
+    let opts = parse_args();
 
   //FIXME contact points should be configurable
     let contact_points = "127.0.0.1";
@@ -27,18 +111,66 @@ fn main() {
       assert!(crustfs.execute(crustfs.cmds.create_ks.to_string()).wait().is_ok());
       assert!(crustfs.execute(crustfs.cmds.drop_inode_table.to_string()).wait().is_ok());
       assert!(crustfs.execute(crustfs.cmds.drop_fs_metadata_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_usage_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_quota_usage_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_quota_limits_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_xattr_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_locks_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_sessions_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_changes_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_unsharded_changes_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_chunks_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_chunk_map_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_rstats_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.drop_rctimes_table.to_string()).wait().is_ok());
       assert!(crustfs.execute(crustfs.cmds.create_inode_table.to_string()).wait().is_ok());
       assert!(crustfs.execute(crustfs.cmds.create_fs_metadata_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_usage_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_quota_usage_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_quota_limits_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_xattr_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_locks_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_sessions_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_changes_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_chunks_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_chunk_map_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_rstats_table.to_string()).wait().is_ok());
+      assert!(crustfs.execute(crustfs.cmds.create_rctimes_table.to_string()).wait().is_ok());
       assert!(crustfs.execute(crustfs.cmds.create_null_inode.to_string()).wait().is_ok());
 
-      let insert_root_inode_statement = CassStatement::new(&crustfs.cmds.create_root_inode,4);
-      let seconds = time::get_time().sec as i64;
-      insert_root_inode_statement.bind_int64(0, seconds).unwrap();
-      insert_root_inode_statement.bind_int64(1, seconds).unwrap(); 
-      insert_root_inode_statement.bind_int64(2, seconds).unwrap(); 
-      insert_root_inode_statement.bind_int64(3, seconds).unwrap();
-      assert!(crustfs.execute_statement(insert_root_inode_statement).wait().is_ok());
-       
+      //only partitions 0, 1 and (with lost+found) 2 get a row here; allocate_inode
+      //starts the others at their own number, see next_inode
+      //the root is linked by its own "." and "..", plus the ".." of every child directory
+      let root_nlink = if opts.lost_found {3} else {2};
+      insert_dir(&crustfs, ROOT_INODE, ROOT_INODE, opts.root_mode, root_nlink, opts.root_uid, opts.root_gid);
+
+      if opts.lost_found {
+          //lost+found is only for root to poke around in, like on ext*
+          insert_dir(&crustfs, LOST_FOUND_INODE, ROOT_INODE, 0o700, 2, 0, 0);
+          let statement = CassStatement::new(&crustfs.cmds.add_inode_to_parent,4);
+          statement.bind_string(0, "lost+found").unwrap();
+          statement.bind_int64(1, LOST_FOUND_INODE as i64).unwrap();
+          statement.bind_int64(2, (ROOT_INODE % INODE_PARTITIONS) as i64).unwrap();
+          statement.bind_int64(3, ROOT_INODE as i64).unwrap();
+          assert!(crustfs.execute_statement(statement).wait().is_ok());
+      }
+      assert!(crustfs.update_usage(0, if opts.lost_found {2} else {1}).is_ok());
+      assert!(crustfs.update_quota_usage(opts.root_uid, opts.root_gid, 0, 0, 1).is_ok());
+      if opts.lost_found {
+          assert!(crustfs.update_quota_usage(0, 0, 0, 0, 1).is_ok());
+          assert!(crustfs.charge_rstats(ROOT_INODE, 0, 0, 1).is_ok());
+      }
+      if let Some(capacity) = opts.capacity {
+          insert_metadata(&crustfs, "capacity", capacity);
+      }
+      if let Some(max_inodes) = opts.max_inodes {
+          insert_metadata(&crustfs, "max_inodes", max_inodes);
+      }
+      if let Some(inline_max) = opts.inline_max {
+          insert_metadata(&crustfs, "inline_max", inline_max);
+      }
+      println!("Root inode created with owner {}:{} and mode {:o}.",
+          opts.root_uid, opts.root_gid, opts.root_mode);
     }
   }
 }
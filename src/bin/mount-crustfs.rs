@@ -6,28 +6,156 @@ extern crate fuse;
 extern crate cql_ffi;
 extern crate crustfs;
 
-use cql_ffi::{CassSession, CassCluster};
+use cql_ffi::CassSession;
 use crustfs::CrustFS;
+use crustfs::atime::AtimePolicy;
+use crustfs::cache::{AttrCache, DEFAULT_ATTR_TTL, DEFAULT_ENTRY_TTL, DEFAULT_CACHE_SIZE};
+use crustfs::cluster::{ClusterConfig, read_password};
+use crustfs::consistency::{ConsistencyLevels, parse_level, is_serial};
+use std::ffi::OsStr;
 use std::path::Path;
 
 use std::env;
+use std::process;
+
+struct Options {
+    mountpoint:String,
+    consistency:ConsistencyLevels,
+    attr_ttl:f64,   //seconds, may be fractional
+    entry_ttl:f64,
+    cache_size:usize,
+    atime:AtimePolicy,
+    subdir:Option<String>,
+    read_only:bool,
+    cluster:ClusterConfig,
+}
+
+fn usage() -> ! {
+    println!("usage: mount-crustfs [--meta-read level] [--meta-write level] [--data-read level]");
+    println!("                     [--data-write level] [--serial level] [--fsync level]");
+    println!("                     [--attr-ttl secs] [--entry-ttl secs] [--cache-size entries]");
+    println!("                     [--atime noatime|relatime|strictatime] [--subdir path]");
+    println!("                     [--read-only] [--contact-points host,...]");
+    println!("                     [--username name --password-file path] mountpoint");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut mountpoint = None;
+    let mut opts = Options{mountpoint:String::new(), consistency:ConsistencyLevels::new(),
+        attr_ttl:DEFAULT_ATTR_TTL, entry_ttl:DEFAULT_ENTRY_TTL, cache_size:DEFAULT_CACHE_SIZE,
+        atime:AtimePolicy::RelAtime, subdir:None, read_only:false, cluster:ClusterConfig::new()};
+    let mut username = None;
+    let mut password = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if mountpoint.is_some() { usage() }
+            mountpoint = Some(arg);
+            continue;
+        }
+        if arg == "--read-only" {
+            opts.read_only = true;
+            continue;
+        }
+        let value = args.next().unwrap_or_else(|| usage());
+        let level = || parse_level(value.as_str()).unwrap_or_else(|| usage());
+        let secs = || match value.parse::<f64>() {
+            Ok(secs) if secs >= 0.0 => secs,
+            _ => usage(),
+        };
+        match arg.as_str() {
+            "--meta-read" => opts.consistency.meta_read = level(),
+            "--meta-write" => opts.consistency.meta_write = level(),
+            "--data-read" => opts.consistency.data_read = level(),
+            "--data-write" => opts.consistency.data_write = level(),
+            "--serial" => {
+                let level = level();
+                if !is_serial(level) { usage() }
+                opts.consistency.serial = level;
+            },
+            "--fsync" => opts.consistency.fsync = Some(level()),
+            "--attr-ttl" => opts.attr_ttl = secs(),
+            "--entry-ttl" => opts.entry_ttl = secs(),
+            "--cache-size" => opts.cache_size = value.parse().unwrap_or_else(|_| usage()),
+            "--atime" => opts.atime = AtimePolicy::parse(value.as_str()).unwrap_or_else(|| usage()),
+            "--subdir" => opts.subdir = Some(value.clone()),
+            "--contact-points" => opts.cluster.contact_points = value.clone(),
+            "--username" => username = Some(value.clone()),
+            "--password-file" => password = Some(read_password(value.as_str()).unwrap_or_else(|err| {
+                println!("unable to read {}: {}", value, err);
+                process::exit(1);
+            })),
+            _ => usage(),
+        }
+    }
+    opts.cluster.credentials = match (username, password) {
+        (Some(username), Some(password)) => Some((username, password)),
+        (None, None) => None,
+        _ => usage(),
+    };
+    opts.mountpoint = mountpoint.unwrap_or_else(|| usage());
+    opts
+}
 
 fn main() {
-    let mp = env::args().nth(1).unwrap();
+    let opts = parse_args();
+    let mp = opts.mountpoint.clone();
     let mountpoint = Path::new(mp.as_str());
-    let contact_points = "127.0.0.1";
-    let cluster = CassCluster::new()
-          .set_contact_points(contact_points).unwrap();
+    let cluster = opts.cluster.cluster();
     let session = CassSession::new();
     match session.connect(&cluster).wait() {
     Err(fail) => println!("fail: {}",fail),
     Ok(session) => {
-      let crustfs = CrustFS::build(session);
-      assert!(crustfs.execute(crustfs.cmds.create_ks.to_string()).wait().is_ok());
-      assert!(crustfs.execute(crustfs.cmds.create_inode_table.to_string()).wait().is_ok());
-      assert!(crustfs.execute(crustfs.cmds.create_fs_metadata_table.to_string()).wait().is_ok());
+      let mut crustfs = CrustFS::build(session);
+      crustfs.cluster_config = opts.cluster.clone();
+      crustfs.consistency = opts.consistency;
+      crustfs.cache = AttrCache::new(opts.attr_ttl, opts.entry_ttl, opts.cache_size);
+      crustfs.atime_policy = opts.atime;
+      crustfs.read_only = opts.read_only;
+      if opts.read_only {
+          //relatime would be writing
+          crustfs.atime_policy = AtimePolicy::NoAtime;
+      } else if let Err(err) = crustfs.upgrade_schema() {
+          println!("unable to bring the schema up to date: {:?}", err);
+          return;
+      }
+      match crustfs.fs_metadata_u64("inline_max") {
+          Ok(Some(inline_max)) => crustfs.inline_max = inline_max,
+          Ok(None) => {},
+          Err(err) => {
+              println!("unable to read fs metadata: {:?}", err);
+              return;
+          }
+      }
+      if let Some(ref subdir) = opts.subdir {
+          //resolved once; renaming it later doesn't move the mount
+          let found = crustfs.resolve_path(subdir.as_str())
+              .and_then(|ino| match ino {Some(ino) => crustfs.get_inode(ino), None => Ok(None)});
+          match found {
+              Ok(Some(ref row)) if row.is_dir() => crustfs.root = row.inode,
+              Ok(Some(_)) => {
+                  println!("{} is not a directory", subdir);
+                  return;
+              },
+              Ok(None) => {
+                  println!("{} does not exist", subdir);
+                  return;
+              },
+              Err(err) => {
+                  println!("unable to resolve {}: {:?}", subdir, err);
+                  return;
+              }
+          }
+      }
+      if let Err(err) = crustfs.register_session(mp.as_str()) {
+          println!("unable to register session: {:?}", err);
+          return;
+      }
       println!("Session Established. Mounting fs.");
-      fuse::mount(crustfs, &mountpoint, &[]);
+      let ro = [OsStr::new("-o"), OsStr::new("ro")];
+      let options:&[&OsStr] = if opts.read_only {&ro} else {&[]};
+      fuse::mount(crustfs, &mountpoint, options);
     }
   }
 }
@@ -0,0 +1,219 @@
+#![feature(libc)]
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+extern crate cql_ffi;
+extern crate crustfs;
+
+use cql_ffi::CassCluster;
+use cql_ffi::CassSession;
+use cql_ffi::CassStatement;
+use crustfs::{CrustFS, InodeRow, INODE_PARTITIONS, BLOCK_SIZE, parse_size};
+use crustfs::quota::{QuotaKind, QuotaLimits, DEFAULT_GRACE};
+
+use std::collections::VecDeque;
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    println!("usage: crustfs-quota set user|group|project id [--bsoft size] [--bhard size]");
+    println!("                     [--isoft count] [--ihard count] [--grace secs]");
+    println!("       crustfs-quota report");
+    println!("       crustfs-quota project assign id /path");
+    println!("       crustfs-quota project recompute id");
+    process::exit(1);
+}
+
+fn set(crustfs: &CrustFS, mut args: env::Args) {
+    let kind = args.next().and_then(|k| QuotaKind::from_str(k.as_str())).unwrap_or_else(|| usage());
+    let id:u32 = args.next().and_then(|id| id.parse().ok()).unwrap_or_else(|| usage());
+
+    //start from the current limits so options that aren't given stay as they were
+    let mut limits = match crustfs.quota_limits(kind, id) {
+        Ok(limits) => limits.unwrap_or(QuotaLimits{bytes_soft:0, bytes_hard:0, inodes_soft:0, inodes_hard:0,
+            grace:DEFAULT_GRACE, bytes_over_since:0, inodes_over_since:0}),
+        Err(err) => {
+            println!("unable to read quota: {:?}", err);
+            process::exit(1);
+        }
+    };
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--bsoft" => limits.bytes_soft = parse_size(value.as_str()).unwrap_or_else(|| usage()),
+            "--bhard" => limits.bytes_hard = parse_size(value.as_str()).unwrap_or_else(|| usage()),
+            "--isoft" => limits.inodes_soft = value.parse().unwrap_or_else(|_| usage()),
+            "--ihard" => limits.inodes_hard = value.parse().unwrap_or_else(|_| usage()),
+            "--grace" => limits.grace = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+    if let Err(err) = crustfs.set_quota_limits(kind, id, &limits) {
+        println!("unable to set quota: {:?}", err);
+        process::exit(1);
+    }
+}
+
+fn fail(what: &str, err: cql_ffi::CassError) -> ! {
+    println!("unable to {}: {:?}", what, err);
+    process::exit(1);
+}
+
+//only files count towards byte usage, with the blocks they take up;
+//directories are just charged an inode
+fn row_bytes(row: &InodeRow) -> i64 {
+    if row.is_dir() {0} else {(row.blocks * BLOCK_SIZE) as i64}
+}
+
+/// Put every inode under path into the project, moving its usage over from
+/// whatever project it was in before. Inodes made later inherit it from
+/// their parent.
+fn assign_project(crustfs: &CrustFS, id: u32, path: &str) {
+    let top = match crustfs.resolve_path(path) {
+        Ok(Some(ino)) => ino,
+        Ok(None) => {
+            println!("{}: no such file or directory", path);
+            process::exit(1);
+        },
+        Err(err) => fail("resolve path", err),
+    };
+    let mut queue = VecDeque::new();
+    queue.push_back(top);
+    let mut moved = 0;
+    while let Some(ino) = queue.pop_front() {
+        let row = match crustfs.get_inode(ino) {
+            Ok(Some(row)) => row,
+            Ok(None) => continue,
+            Err(err) => fail("read inode", err),
+        };
+        queue.extend(row.dir_contents.values().cloned());
+        if row.project == id {
+            continue;
+        }
+        let statement = CassStatement::new(crustfs.cmds.update_project, 3);
+        statement.bind_int64(0, id as i64).unwrap();
+        statement.bind_int64(1, row.part_id as i64).unwrap();
+        statement.bind_int64(2, row.inode as i64).unwrap();
+        if let Err(err) = crustfs.execute_statement(statement).wait() {
+            fail("update inode", err);
+        }
+        if row.project != 0 {
+            if let Err(err) = crustfs.charge_quota(QuotaKind::Project, row.project, -row_bytes(&row), -1) {
+                fail("update quota usage", err);
+            }
+        }
+        if let Err(err) = crustfs.charge_quota(QuotaKind::Project, id, row_bytes(&row), 1) {
+            fail("update quota usage", err);
+        }
+        moved += 1;
+    }
+    println!("moved {} inodes under {} into project {}", moved, path, id);
+}
+
+/// Count up everything in the project from scratch and fix the counters to match.
+fn recompute_project(crustfs: &CrustFS, id: u32) {
+    let (mut bytes, mut inodes) = (0, 0);
+    for partition in 0..INODE_PARTITIONS {
+        let rows = match crustfs.select_partition(partition) {
+            Ok(rows) => rows,
+            Err(err) => fail("read inode partition", err),
+        };
+        for row in rows.iter().filter(|row| row.project == id && !row.is_placeholder()) {
+            bytes += row_bytes(row);
+            inodes += 1;
+        }
+    }
+    let usage = match crustfs.quota_usage(QuotaKind::Project, id) {
+        Ok(usage) => usage,
+        Err(err) => fail("read quota usage", err),
+    };
+    println!("project {}: counters say {} bytes in {} inodes, found {} bytes in {} inodes",
+        id, usage.bytes, usage.inodes, bytes, inodes);
+    //counters can only be incremented, so add the difference
+    if let Err(err) = crustfs.charge_quota(QuotaKind::Project, id, bytes - usage.bytes, inodes - usage.inodes) {
+        fail("update quota usage", err);
+    }
+}
+
+fn project(crustfs: &CrustFS, mut args: env::Args) {
+    let command = args.next().unwrap_or_else(|| usage());
+    let id:u32 = args.next().and_then(|id| id.parse().ok()).unwrap_or_else(|| usage());
+    if id == 0 {
+        usage();
+    }
+    match command.as_str() {
+        "assign" => assign_project(crustfs, id, args.next().unwrap_or_else(|| usage()).as_str()),
+        "recompute" => recompute_project(crustfs, id),
+        _ => usage(),
+    }
+}
+
+fn grace_left(over_since: i64, grace: i64, now: i64) -> String {
+    if over_since == 0 {
+        "-".to_string()
+    } else if now - over_since >= grace {
+        "none".to_string()
+    } else {
+        format!("{}s", grace - (now - over_since))
+    }
+}
+
+/// Print usage and limits for every user, group and project that has ever been charged, like repquota.
+fn report(crustfs: &CrustFS) {
+    let statement = CassStatement::new(crustfs.cmds.select_all_quota_usage, 0);
+    let result = match crustfs.execute_statement(statement).wait() {
+        Ok(result) => result,
+        Err(err) => {
+            println!("unable to read quota usage: {:?}", err);
+            process::exit(1);
+        }
+    };
+    let now = time::get_time().sec;
+    println!("{:<7} {:>10} {:>14} {:>14} {:>14} {:>8} {:>10} {:>10} {:>10} {:>8}",
+        "kind", "id", "bytes", "soft", "hard", "grace", "files", "soft", "hard", "grace");
+    for row in result.iter() {
+        let kind = row.get_column(0).unwrap().get_string().unwrap().to_string();
+        let id = row.get_column(1).unwrap().get_int64().unwrap() as u32;
+        let bytes = row.get_column(2).unwrap().get_int64().unwrap_or(0);
+        let inodes = row.get_column(3).unwrap().get_int64().unwrap_or(0);
+        let limits = QuotaKind::from_str(kind.as_str())
+            .and_then(|kind| crustfs.quota_limits(kind, id).ok())
+            .and_then(|limits| limits);
+        match limits {
+            None => println!("{:<7} {:>10} {:>14} {:>14} {:>14} {:>8} {:>10} {:>10} {:>10} {:>8}",
+                kind, id, bytes, 0, 0, "-", inodes, 0, 0, "-"),
+            Some(l) => println!("{:<7} {:>10} {:>14} {:>14} {:>14} {:>8} {:>10} {:>10} {:>10} {:>8}",
+                kind, id, bytes, l.bytes_soft, l.bytes_hard, grace_left(l.bytes_over_since, l.grace, now),
+                inodes, l.inodes_soft, l.inodes_hard, grace_left(l.inodes_over_since, l.grace, now)),
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args();
+    args.next();
+    let command = args.next().unwrap_or_else(|| usage());
+
+    //FIXME contact points should be configurable
+    let contact_points = "127.0.0.1";
+    let cluster = CassCluster::new()
+          .set_contact_points(contact_points).unwrap();
+    let session = CassSession::new();
+    match session.connect(&cluster).wait() {
+    Err(fail) => println!("fail: {}",fail),
+    Ok(session) => {
+      let crustfs = CrustFS::build(session);
+      if let Err(err) = crustfs.upgrade_schema() {
+          println!("unable to bring the schema up to date: {:?}", err);
+          process::exit(1);
+      }
+      match command.as_str() {
+          "set" => set(&crustfs, args),
+          "report" => report(&crustfs),
+          "project" => project(&crustfs, args),
+          _ => usage(),
+      }
+    }
+  }
+}
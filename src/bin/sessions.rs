@@ -0,0 +1,49 @@
+#![feature(libc)]
+
+extern crate libc;
+extern crate time;
+extern crate fuse;
+extern crate cql_ffi;
+extern crate crustfs;
+
+use cql_ffi::CassCluster;
+use cql_ffi::CassSession;
+use crustfs::CrustFS;
+
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+    println!("usage: crustfs-sessions");
+    process::exit(1);
+}
+
+fn main() {
+    if env::args().len() > 1 { usage() }
+
+    //FIXME contact points should be configurable
+    let contact_points = "127.0.0.1";
+    let cluster = CassCluster::new()
+          .set_contact_points(contact_points).unwrap();
+    let session = CassSession::new();
+    match session.connect(&cluster).wait() {
+    Err(fail) => println!("fail: {}",fail),
+    Ok(session) => {
+      let crustfs = CrustFS::build(session);
+      let mut sessions = match crustfs.list_sessions() {
+          Ok(sessions) => sessions,
+          Err(err) => {
+              println!("unable to list sessions: {:?}", err);
+              process::exit(1);
+          }
+      };
+      sessions.sort_by(|a, b| a.mounted.cmp(&b.mounted));
+      println!("{:<20} {:<20} {:>7} {:<19} {:<8} {}", "client", "host", "pid", "mounted", "version", "mountpoint");
+      for s in sessions.iter() {
+          let mounted = time::at(time::Timespec::new(s.mounted, 0));
+          println!("{:<20} {:<20} {:>7} {:<19} {:<8} {}", s.client_id, s.host, s.pid,
+              time::strftime("%Y-%m-%d %H:%M:%S", &mounted).unwrap(), s.version, s.mountpoint);
+      }
+    }
+  }
+}
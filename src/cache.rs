@@ -0,0 +1,242 @@
+//! Client side attribute and dentry cache.
+//!
+//! Attributes are cached by inode and directory entries by (parent, name),
+//! including negative entries for names that don't exist, each for its own
+//! TTL. Both maps are bounded: once full, the oldest insertions are evicted
+//! first. Mutations made through this mount invalidate entries right away,
+//! those made by other clients once the change log tail picks them up.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+use libc::{c_int, EIO, ENOENT, ENOTDIR};
+
+use fuse::FileAttr;
+
+use time::{self, Timespec};
+
+use CrustFS;
+
+//used when mount-crustfs isn't told otherwise
+pub static DEFAULT_ATTR_TTL:f64 = 1.0;
+pub static DEFAULT_ENTRY_TTL:f64 = 1.0;
+pub static DEFAULT_CACHE_SIZE:usize = 65536;
+
+/// Turn a TTL in (fractional) seconds into a Timespec.
+pub fn ttl_from_secs(secs: f64) -> Timespec {
+    Timespec::new(secs.trunc() as i64, (secs.fract() * 1e9) as i32)
+}
+
+/// A map whose entries expire, holding at most capacity of them.
+struct TtlMap<K, V> {
+    map:HashMap<K, (V, Timespec, u64)>,
+    //insertion order, for eviction; entries whose seq no longer matches are stale
+    order:VecDeque<(K, u64)>,
+    seq:u64,
+    capacity:usize,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> TtlMap<K, V> {
+    fn new(capacity: usize) -> TtlMap<K, V> {
+        TtlMap{map:HashMap::new(), order:VecDeque::new(), seq:0, capacity:capacity}
+    }
+
+    fn get(&self, key: &K, now: Timespec) -> Option<V> {
+        match self.map.get(key) {
+            Some(&(ref value, expires, _)) if expires > now => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: K, value: V, expires: Timespec) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.seq += 1;
+        self.map.insert(key.clone(), (value, expires, self.seq));
+        self.order.push_back((key, self.seq));
+        while self.map.len() > self.capacity {
+            match self.order.pop_front() {
+                Some((key, seq)) => {
+                    if self.map.get(&key).map(|entry| entry.2 == seq).unwrap_or(false) {
+                        self.map.remove(&key);
+                    }
+                },
+                None => break,
+            }
+        }
+        //keys inserted over and over leave stale entries behind in order
+        if self.order.len() > 2 * self.capacity {
+            let map = &self.map;
+            let live:VecDeque<(K, u64)> = self.order.drain(..)
+                .filter(|&(ref key, seq)| map.get(key).map(|entry| entry.2 == seq).unwrap_or(false))
+                .collect();
+            self.order = live;
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.map.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+}
+
+pub struct AttrCache {
+    attrs:TtlMap<u64, FileAttr>,
+    //None is a negative entry
+    entries:TtlMap<(u64, String), Option<u64>>,
+    //inline data of small files, read along with their attributes
+    inline:TtlMap<u64, Option<Vec<u8>>>,
+    pub attr_ttl:Timespec,
+    pub entry_ttl:Timespec,
+}
+
+fn expiry(ttl: Timespec) -> Timespec {
+    time::get_time() + time::Duration::seconds(ttl.sec) + time::Duration::nanoseconds(ttl.nsec as i64)
+}
+
+impl AttrCache {
+    pub fn new(attr_ttl: f64, entry_ttl: f64, capacity: usize) -> AttrCache {
+        AttrCache{
+            attrs:TtlMap::new(capacity),
+            entries:TtlMap::new(capacity),
+            inline:TtlMap::new(capacity),
+            attr_ttl:ttl_from_secs(attr_ttl),
+            entry_ttl:ttl_from_secs(entry_ttl),
+        }
+    }
+
+    pub fn attr(&self, ino: u64) -> Option<FileAttr> {
+        self.attrs.get(&ino, time::get_time())
+    }
+
+    pub fn insert_attr(&mut self, attr: &FileAttr) {
+        let expires = expiry(self.attr_ttl);
+        self.attrs.insert(attr.ino, attr.clone(), expires);
+    }
+
+    pub fn invalidate_attr(&mut self, ino: u64) {
+        self.attrs.remove(&ino);
+        self.inline.remove(&ino);
+    }
+
+    /// Some(None) means the file is stored in chunks.
+    pub fn inline(&self, ino: u64) -> Option<Option<Vec<u8>>> {
+        self.inline.get(&ino, time::get_time())
+    }
+
+    pub fn insert_inline(&mut self, ino: u64, data: Option<Vec<u8>>) {
+        let expires = expiry(self.attr_ttl);
+        self.inline.insert(ino, data, expires);
+    }
+
+    /// Some(None) means name is cached as not existing.
+    pub fn entry(&self, parent: u64, name: &str) -> Option<Option<u64>> {
+        self.entries.get(&(parent, name.to_string()), time::get_time())
+    }
+
+    pub fn insert_entry(&mut self, parent: u64, name: &str, ino: Option<u64>) {
+        let expires = expiry(self.entry_ttl);
+        self.entries.insert((parent, name.to_string()), ino, expires);
+    }
+
+    pub fn invalidate_entry(&mut self, parent: u64, name: &str) {
+        self.entries.remove(&(parent, name.to_string()));
+    }
+
+    pub fn clear(&mut self) {
+        self.attrs.clear();
+        self.entries.clear();
+        self.inline.clear();
+    }
+}
+
+impl CrustFS {
+    /// Attributes of ino, from the cache while they are fresh.
+    pub fn cached_attr(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        self.apply_invalidations();
+        if let Some(attr) = self.cache.attr(ino) {
+            return Ok(self.with_local_changes(attr));
+        }
+        //our own size updates may still be on their way
+        self.wait_writes(ino);
+        match self.get_inode(ino) {
+            Ok(Some(ref row)) if !row.is_placeholder() => {
+                let attr = row.to_attr();
+                self.cache.insert_attr(&attr);
+                self.cache.insert_inline(ino, row.inline_data.clone());
+                Ok(self.with_local_changes(attr))
+            },
+            Ok(_) => Err(ENOENT),
+            Err(err) => {
+                error!("cached_attr: unable to read inode {}: {:?}", ino, err);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Writes still in our buffer already count towards the size we report,
+    /// and access times not written yet towards the atime.
+    fn with_local_changes(&self, mut attr: FileAttr) -> FileAttr {
+        if let (Some(size), Some(blocks)) = (self.buffered_size(attr.ino), self.buffered_blocks(attr.ino)) {
+            attr.size = size;
+            attr.blocks = blocks;
+        }
+        if let Some(atime) = self.atimes.lock().unwrap().get(attr.ino) {
+            attr.atime = atime;
+        }
+        attr
+    }
+
+    /// The inode parent has under name, from the cache while it is fresh.
+    /// Misses are cached too, so repeated stats of a missing file stay local.
+    pub fn lookup_child(&mut self, parent: u64, name: &str) -> Result<u64, c_int> {
+        self.apply_invalidations();
+        match self.cache.entry(parent, name) {
+            Some(Some(ino)) => return Ok(ino),
+            Some(None) => return Err(ENOENT),
+            None => {}
+        }
+        let row = match self.get_inode(parent) {
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(ENOENT),
+            Err(err) => {
+                error!("lookup_child: unable to read inode {}: {:?}", parent, err);
+                return Err(EIO);
+            }
+        };
+        if !row.is_dir() {
+            return Err(ENOTDIR);
+        }
+        self.cache.insert_attr(&row.to_attr());
+        let child = row.dir_contents.get(name).cloned();
+        self.cache.insert_entry(parent, name, child);
+        child.ok_or(ENOENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TtlMap;
+    use time::{self, Duration};
+
+    #[test]
+    fn evicts_oldest_and_expires() {
+        let now = time::get_time();
+        let later = now + Duration::seconds(10);
+        let mut map = TtlMap::new(2);
+        map.insert(1, "a", later);
+        map.insert(2, "b", later);
+        map.insert(1, "a2", later);
+        map.insert(3, "c", later);
+        //2 is the oldest live insertion once 1 was refreshed
+        assert_eq!(map.get(&2, now), None);
+        assert_eq!(map.get(&1, now), Some("a2"));
+        assert_eq!(map.get(&3, now), Some("c"));
+        assert_eq!(map.get(&3, later), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Cross-client cache coherence.
+//!
+//! Every metadata change made through a mount is appended to crustfs.change_log,
+//! partitioned into CHANGE_BUCKET second buckets so a reader only ever touches
+//! the last one or two of them. Each bucket is split again into CHANGE_SHARDS
+//! partitions by inode, so that a busy filesystem doesn't pile all of its
+//! writes for ten seconds onto the replicas of one partition; a reader polls
+//! every shard of the buckets it reads. Each mount tails the log from a thread of
+//! its own and queues what other clients changed; the fuse loop drops the
+//! matching entries from its attribute and dentry cache, and the pages it
+//! read ahead, before using them.
+//! rust-fuse has no way to send the kernel notify_inval_* messages, so the
+//! kernel's own cache still lives out the TTL we handed it.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use cql_ffi::{CassSession, CassStatement, CassError};
+use cql_ffi::result_future::ResultFuture;
+
+use rand::{self, Rng};
+
+use time;
+
+use CrustFS;
+
+//seconds of log per bucket
+pub static CHANGE_BUCKET:i64 = 10;
+//partitions per bucket
+pub static CHANGE_SHARDS:u64 = 16;
+//how long a change stays in the log, in seconds
+pub static CHANGE_TTL:i32 = 3600;
+//how often the log is polled, in milliseconds
+pub static CHANGE_POLL:u32 = 1000;
+//each poll reaches this far back, in milliseconds, so that changes written
+//by a client whose clock is slightly behind ours, or that arrived late, are
+//still seen. Seeing a change twice is harmless.
+pub static CHANGE_SKEW:i64 = 5000;
+//queued changes the fuse loop hasn't applied yet; past this the whole cache is dropped instead
+static MAX_QUEUED:usize = 10000;
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    //0 (never a real inode) stands for "everything"
+    pub inode:u64,
+    //set when the change added, removed or renamed the entry name in parent
+    pub parent:Option<(u64, String)>,
+}
+
+pub fn new_invalidations() -> Arc<Mutex<Vec<Change>>> {
+    Arc::new(Mutex::new(Vec::new()))
+}
+
+fn now_ms() -> i64 {
+    let now = time::get_time();
+    now.sec * 1000 + (now.nsec / 1000000) as i64
+}
+
+impl CrustFS {
+    /// Append a change to the log. Failing to is logged, not returned: the
+    /// change itself already happened, other clients just see it after their TTL.
+    pub fn log_change(&self, ino: u64, parent: Option<(u64, &str)>) {
+        let ts = now_ms();
+        let statement = CassStatement::new(self.cmds.insert_change, 9);
+        statement.bind_int64(0, ts / (CHANGE_BUCKET * 1000)).unwrap();
+        statement.bind_int32(1, (ino % CHANGE_SHARDS) as i32).unwrap();
+        statement.bind_int64(2, ts).unwrap();
+        statement.bind_int64(3, rand::thread_rng().gen()).unwrap();
+        statement.bind_int64(4, self.client_id as i64).unwrap();
+        statement.bind_int64(5, ino as i64).unwrap();
+        match parent {
+            Some((parent, name)) => {
+                statement.bind_int64(6, parent as i64).unwrap();
+                statement.bind_string(7, name).unwrap();
+            },
+            None => {
+                statement.bind_int64(6, 0).unwrap();
+                statement.bind_string(7, "").unwrap();
+            }
+        }
+        statement.bind_int32(8, CHANGE_TTL).unwrap();
+        if let Err(err) = self.write_meta(&statement).wait() {
+            error!("log_change: unable to log change to {}: {:?}", ino, err);
+        }
+    }
+
+    /// Changes made by other clients at or after since (in milliseconds).
+    fn select_changes(&self, since: i64) -> Result<Vec<Change>, CassError> {
+        let mut changes = Vec::new();
+        let (first, last) = (since / (CHANGE_BUCKET * 1000), now_ms() / (CHANGE_BUCKET * 1000));
+        let mut reads:Vec<(CassStatement, ResultFuture)> = Vec::new();
+        for bucket in first..last + 1 {
+            for shard in 0..CHANGE_SHARDS {
+                let statement = CassStatement::new(self.cmds.select_changes, 3);
+                statement.bind_int64(0, bucket).unwrap();
+                statement.bind_int32(1, shard as i32).unwrap();
+                statement.bind_int64(2, since).unwrap();
+                let future = self.read_meta(&statement);
+                reads.push((statement, future));
+            }
+        }
+        for (_statement, mut future) in reads.into_iter() {
+            let result = try!(future.wait());
+            for row in result.iter() {
+                if row.get_column(0).unwrap().get_int64().unwrap() as u64 == self.client_id {
+                    continue;
+                }
+                let parent = row.get_column(2).unwrap().get_int64().unwrap_or(0) as u64;
+                changes.push(Change{
+                    inode:row.get_column(1).unwrap().get_int64().unwrap() as u64,
+                    parent:if parent == 0 {None} else {
+                        Some((parent, row.get_column(3).unwrap().get_string().unwrap().to_string()))
+                    },
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Drop whatever the tail thread has found out is stale. Called before the
+    /// cache is consulted.
+    pub fn apply_invalidations(&mut self) {
+        let changes:Vec<Change> = self.invalidations.lock().unwrap().drain(..).collect();
+        for change in changes.into_iter() {
+            if change.inode == 0 {
+                self.cache.clear();
+                self.drop_all_pages();
+                continue;
+            }
+            self.cache.invalidate_attr(change.inode);
+            self.drop_pages(change.inode);
+            if let Some((parent, ref name)) = change.parent {
+                self.cache.invalidate_attr(parent);
+                self.cache.invalidate_entry(parent, name.as_str());
+            }
+        }
+    }
+
+    /// Start the thread that tails the change log. It uses its own connection,
+    /// since the one in self belongs to the fuse loop.
+    pub fn start_change_tail(&self) {
+        let cluster_config = self.cluster_config.clone();
+        let client_id = self.client_id;
+        let consistency = self.consistency;
+        let invalidations = self.invalidations.clone();
+        thread::spawn(move || {
+            let cluster = cluster_config.cluster();
+            let session = match CassSession::new().connect(&cluster).wait() {
+                Ok(session) => session,
+                Err(err) => {
+                    error!("change tail: unable to connect, other clients' changes show up after the TTL: {:?}", err);
+                    return;
+                }
+            };
+            let mut crustfs = CrustFS::build(session);
+            crustfs.client_id = client_id;
+            crustfs.consistency = consistency;
+            let mut since = now_ms();
+            loop {
+                thread::sleep_ms(CHANGE_POLL);
+                let polled = now_ms();
+                match crustfs.select_changes(since - CHANGE_SKEW) {
+                    Ok(changes) => {
+                        if !changes.is_empty() {
+                            debug!("change tail: {} changes from other clients", changes.len());
+                            let mut queued = invalidations.lock().unwrap();
+                            if queued.len() + changes.len() > MAX_QUEUED {
+                                queued.clear();
+                                queued.push(Change{inode:0, parent:None});
+                            } else {
+                                queued.extend(changes.into_iter());
+                            }
+                        }
+                        since = polled;
+                    },
+                    //since stays put, so the next poll covers what this one missed
+                    Err(err) => error!("change tail: unable to read change log: {:?}", err),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,119 @@
+//! chmod and chown, for setattr.
+//!
+//! Only the owner (or root) may change the mode. A file with an access ACL
+//! keeps it in step: chmod rewrites its owner, mask (or owning group) and
+//! other entries, just as setting the ACL rewrites the mode bits, so
+//! setfacl and ls -l agree. Changing the owner or group is reserved for root,
+//! and moves the file's usage from the old owner's quotas to the new one's.
+
+use libc::{c_int, EIO};
+
+use cql_ffi::CassStatement;
+
+use acl::{Acl, ACL_ACCESS};
+use inode::InodeRow;
+use time;
+use {CrustFS, BLOCK_SIZE};
+
+/// May uid give row the owner new_uid and group new_gid? Only root can,
+/// though anyone who owns the file may "change" them to what they are.
+pub fn may_chown(owner: u32, group: u32, uid: u32, new_uid: Option<u32>, new_gid: Option<u32>) -> bool {
+    uid == 0 || (uid == owner && new_uid.unwrap_or(owner) == owner && new_gid.unwrap_or(group) == group)
+}
+
+/// The mode chmod by uid/gid stores. As on Linux, the setgid bit goes unless
+/// the caller is root or in the file's group.
+pub fn chmod_mode(row: &InodeRow, uid: u32, gid: u32, mode: u32) -> u32 {
+    let mode = mode & 0o7777;
+    if uid != 0 && gid != row.gid {mode & !0o2000} else {mode}
+}
+
+impl CrustFS {
+    /// Store perm as the mode of row, moving its ctime.
+    pub fn update_perm(&mut self, row: &InodeRow, perm: u32) -> Result<(), c_int> {
+        self.cache.invalidate_attr(row.inode);
+        self.log_change(row.inode, None);
+        let now = time::get_time();
+        let statement = CassStatement::new(self.cmds.update_perm, 5);
+        statement.bind_int32(0, perm as i32).unwrap();
+        statement.bind_int64(1, now.sec).unwrap();
+        statement.bind_int32(2, now.nsec).unwrap();
+        statement.bind_int64(3, row.part_id as i64).unwrap();
+        statement.bind_int64(4, row.inode as i64).unwrap();
+        match self.write_meta(&statement).wait() {
+            Ok(_) => {
+                self.note_rstats(row.inode, 0, 0, 0, now);
+                Ok(())
+            },
+            Err(err) => {
+                error!("update_perm: unable to update mode of inode {}: {:?}", row.inode, err);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// chmod, once the caller is known to own row.
+    pub fn change_mode(&mut self, row: &InodeRow, perm: u32) -> Result<(), c_int> {
+        let acl = match self.select_xattr(row.inode, ACL_ACCESS) {
+            Ok(acl) => acl.and_then(|bytes| Acl::parse(&bytes).ok()),
+            Err(err) => {
+                error!("chmod: unable to read ACL of inode {}: {:?}", row.inode, err);
+                return Err(EIO);
+            }
+        };
+        if let Some(acl) = acl {
+            if let Err(err) = self.store_xattr(row.inode, ACL_ACCESS, &acl.with_mode(perm).to_bytes()) {
+                error!("chmod: unable to update ACL of inode {}: {:?}", row.inode, err);
+                return Err(EIO);
+            }
+        }
+        self.update_perm(row, perm)
+    }
+
+    /// chown, once the caller is known to be allowed to. row must reflect
+    /// anything buffered for the file, so that its blocks are what quotas charged.
+    pub fn change_owner(&mut self, row: &InodeRow, new_uid: Option<u32>, new_gid: Option<u32>) -> Result<(), c_int> {
+        let (uid, gid) = (new_uid.unwrap_or(row.uid), new_gid.unwrap_or(row.gid));
+        if uid == row.uid && gid == row.gid {
+            return Ok(());
+        }
+        self.cache.invalidate_attr(row.inode);
+        self.log_change(row.inode, None);
+        let now = time::get_time();
+        let statement = CassStatement::new(self.cmds.update_owner, 6);
+        statement.bind_int32(0, uid as i32).unwrap();
+        statement.bind_int32(1, gid as i32).unwrap();
+        statement.bind_int64(2, now.sec).unwrap();
+        statement.bind_int32(3, now.nsec).unwrap();
+        statement.bind_int64(4, row.part_id as i64).unwrap();
+        statement.bind_int64(5, row.inode as i64).unwrap();
+        if let Err(err) = self.write_meta(&statement).wait() {
+            error!("chown: unable to update owner of inode {}: {:?}", row.inode, err);
+            return Err(EIO);
+        }
+        self.note_rstats(row.inode, 0, 0, 0, now);
+        //directories are charged an inode and no bytes, see make_inode
+        let bytes = if row.is_dir() {0} else {(row.blocks * BLOCK_SIZE) as i64};
+        let moved = self.update_quota_usage(row.uid, row.gid, row.project, -bytes, -1)
+            .and(self.update_quota_usage(uid, gid, row.project, bytes, 1));
+        if let Err(err) = moved {
+            error!("chown: unable to move quota usage of inode {}: {:?}", row.inode, err);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::may_chown;
+
+    #[test]
+    fn only_root_gives_files_away() {
+        assert!(may_chown(1000, 100, 0, Some(1001), Some(200)));
+        assert!(!may_chown(1000, 100, 1000, Some(1001), None));
+        assert!(!may_chown(1000, 100, 1000, None, Some(200)));
+        assert!(!may_chown(1000, 100, 1001, Some(1000), None));
+        //chown to what it already is, as cp -p does
+        assert!(may_chown(1000, 100, 1000, Some(1000), Some(100)));
+    }
+}
@@ -0,0 +1,65 @@
+//! Where the cluster is and how to log in to it.
+//!
+//! A mount connects more than once: the fuse loop, the session heartbeat, the
+//! lock waiter and the change log tail each have a connection of their own.
+//! They all build it from the ClusterConfig in CrustFS, so a mount given
+//! other contact points or a username uses them everywhere.
+
+use std::fs::File;
+use std::io::{self, Read};
+
+use cql_ffi::CassCluster;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClusterConfig {
+    //comma separated hosts, as the driver takes them
+    pub contact_points:String,
+    //username and password for Cassandra's PasswordAuthenticator
+    pub credentials:Option<(String, String)>,
+}
+
+impl ClusterConfig {
+    pub fn new() -> ClusterConfig {
+        ClusterConfig{contact_points:"127.0.0.1".to_string(), credentials:None}
+    }
+
+    /// A driver cluster to connect to, with the credentials set if there are any.
+    pub fn cluster(&self) -> CassCluster {
+        let cluster = CassCluster::new().set_contact_points(self.contact_points.as_str()).unwrap();
+        match self.credentials {
+            Some((ref username, ref password)) => cluster.set_credentials(username.as_str(), password.as_str()).unwrap(),
+            None => cluster,
+        }
+    }
+}
+
+/// The password kept in the first line of path. Taking it from a file keeps
+/// it out of the process list.
+pub fn read_password(path: &str) -> io::Result<String> {
+    let mut text = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+    Ok(text.lines().next().unwrap_or("").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    use super::read_password;
+
+    #[test]
+    fn password_is_first_line() {
+        let path = env::temp_dir().join("crustfs-password-test");
+        File::create(&path).and_then(|mut f| f.write_all(b"s3cret pass\nignored\n")).unwrap();
+        let password = read_password(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(password.unwrap(), "s3cret pass");
+    }
+
+    #[test]
+    fn missing_password_file() {
+        assert!(read_password("/nonexistent/crustfs-password").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! Consistency levels, chosen per class of operation.
+//!
+//! Metadata (inodes, directory entries, xattrs, quotas, locks, sessions) and
+//! file data are read and written at separately configurable levels, and every
+//! lightweight transaction runs its Paxos round at the serial level. fsync can
+//! name a stronger level for the writes sent in the background, the ones it
+//! waits for, so a cluster can run at ONE day to day and still have fsync
+//! mean "on a quorum".
+
+use cql_ffi::{CassConsistency, CassStatement};
+use cql_ffi::result_future::ResultFuture;
+
+use CrustFS;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OpClass {
+    MetaRead,
+    MetaWrite,
+    DataRead,
+    DataWrite,
+    //writes fsync waits for, when an fsync level is set (see sync.rs)
+    Fsync,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConsistencyLevels {
+    pub meta_read:CassConsistency,
+    pub meta_write:CassConsistency,
+    pub data_read:CassConsistency,
+    pub data_write:CassConsistency,
+    pub serial:CassConsistency,
+    //None leaves fsync writes at data_write
+    pub fsync:Option<CassConsistency>,
+}
+
+impl ConsistencyLevels {
+    /// What the driver would use if we didn't set anything.
+    pub fn new() -> ConsistencyLevels {
+        ConsistencyLevels{
+            meta_read:CassConsistency::ONE,
+            meta_write:CassConsistency::ONE,
+            data_read:CassConsistency::ONE,
+            data_write:CassConsistency::ONE,
+            serial:CassConsistency::SERIAL,
+            fsync:None,
+        }
+    }
+
+    pub fn level(&self, class: OpClass) -> CassConsistency {
+        match class {
+            OpClass::MetaRead => self.meta_read,
+            OpClass::MetaWrite => self.meta_write,
+            OpClass::DataRead => self.data_read,
+            OpClass::DataWrite => self.data_write,
+            OpClass::Fsync => self.fsync.unwrap_or(self.data_write),
+        }
+    }
+}
+
+/// Parse a level the way cqlsh spells it, e.g. "local_quorum".
+pub fn parse_level(name: &str) -> Option<CassConsistency> {
+    match name.to_uppercase().as_str() {
+        "ANY" => Some(CassConsistency::ANY),
+        "ONE" => Some(CassConsistency::ONE),
+        "TWO" => Some(CassConsistency::TWO),
+        "THREE" => Some(CassConsistency::THREE),
+        "QUORUM" => Some(CassConsistency::QUORUM),
+        "ALL" => Some(CassConsistency::ALL),
+        "LOCAL_QUORUM" => Some(CassConsistency::LOCAL_QUORUM),
+        "EACH_QUORUM" => Some(CassConsistency::EACH_QUORUM),
+        "LOCAL_ONE" => Some(CassConsistency::LOCAL_ONE),
+        "SERIAL" => Some(CassConsistency::SERIAL),
+        "LOCAL_SERIAL" => Some(CassConsistency::LOCAL_SERIAL),
+        _ => None,
+    }
+}
+
+/// Only SERIAL and LOCAL_SERIAL are valid for the Paxos phase of an LWT.
+pub fn is_serial(level: CassConsistency) -> bool {
+    match level {
+        CassConsistency::SERIAL | CassConsistency::LOCAL_SERIAL => true,
+        _ => false,
+    }
+}
+
+impl CrustFS {
+    /// Execute statement at the level configured for class.
+    pub fn execute_as(&self, class: OpClass, statement: &CassStatement) -> ResultFuture {
+        statement.set_consistency(self.consistency.level(class)).unwrap();
+        self.session.execute_statement(statement)
+    }
+
+    pub fn read_meta(&self, statement: &CassStatement) -> ResultFuture {
+        self.execute_as(OpClass::MetaRead, statement)
+    }
+
+    pub fn write_meta(&self, statement: &CassStatement) -> ResultFuture {
+        self.execute_as(OpClass::MetaWrite, statement)
+    }
+
+    pub fn read_data(&self, statement: &CassStatement) -> ResultFuture {
+        self.execute_as(OpClass::DataRead, statement)
+    }
+
+    pub fn write_data(&self, statement: &CassStatement) -> ResultFuture {
+        self.execute_as(OpClass::DataWrite, statement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_level, is_serial};
+
+    #[test]
+    fn levels() {
+        assert!(parse_level("local_quorum").is_some());
+        assert!(parse_level("LOCAL_SERIAL").map(is_serial).unwrap());
+        assert!(!parse_level("quorum").map(is_serial).unwrap());
+        assert!(parse_level("most").is_none());
+    }
+}
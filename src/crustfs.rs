@@ -15,69 +15,124 @@ use cql_ffi::result_future::ResultFuture;
 
 use fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry,
            ReplyAttr, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyCreate,
-           ReplyStatfs, ReplyWrite, ReplyLock, ReplyBmap};
+           ReplyStatfs, ReplyWrite, ReplyLock, ReplyBmap, ReplyXattr};
 
 
 use std::string::ToString;
 use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use rand::Rng;
 
 //use std::fs::{FilePermission, USER_FILE, USER_DIR};
-use std::fs::PathExt;
 use std::ffi::OsStr;
 
 use libc::consts::os::posix88::EIO;
 use libc::c_int;
 use libc::ENOENT;
 use libc::ENOSYS;
+use libc::ENOTDIR;
+use libc::EINVAL;
+use libc::ERANGE;
+use libc::EACCES;
+use libc::EROFS;
+use libc::EEXIST;
+use libc::EPERM;
 
 use time::Timespec;
 
 use cql_ffi::CassStatement;
 use cql_ffi::CassSession;
-use cql_ffi::CassValueType;
-use cql_ffi::CassResult;
-
-static INODE_PARTITIONS:u64=5;
-
-
-static HELLO_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: Timespec { sec: 1381237736, nsec: 0 },
-    mtime: Timespec { sec: 1381237736, nsec: 0 },
-    ctime: Timespec { sec: 1381237736, nsec: 0 },
-    crtime: Timespec { sec: 1381237736, nsec: 0 },
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-};
-
-static HELLO_TXT_ATTR: FileAttr = FileAttr {
-    ino: 2,
-    size: 15,
-    blocks: 1,
-    atime: Timespec { sec: 1381237736, nsec: 0 },
-    mtime: Timespec { sec: 1381237736, nsec: 0 },
-    ctime: Timespec { sec: 1381237736, nsec: 0 },
-    crtime: Timespec { sec: 1381237736, nsec: 0 },
-    kind: FileType::RegularFile,
-    perm: 0o755,
-    nlink: 1,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-};
-
-
-static TTL: Timespec = Timespec { sec: 1, nsec: 0 };    // 1 second
+use cql_ffi::CassError;
+
+pub use inode::InodeRow;
+use inode::dir_size;
+
+use access::{W_OK, X_OK, owns};
+use acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
+use atime::{AtimePolicy, Atimes, only_now};
+use cache::AttrCache;
+use changelog::Change;
+use chmod::{chmod_mode, may_chown};
+use cluster::ClusterConfig;
+use consistency::{ConsistencyLevels, OpClass};
+use data::WriteBuffer;
+use handle::{Handle, FOPEN_DIRECT_IO, refuses_on_read_only};
+use lock::{Lock, LockWaiter, F_UNLCK, F_WRLCK, LOCK_LEASE};
+use rstats::RStatChanges;
+use session::SessionInfo;
+use sync::Pending;
+
+pub mod access;
+pub mod acl;
+pub mod atime;
+pub mod cache;
+pub mod changelog;
+pub mod chmod;
+pub mod cluster;
+pub mod consistency;
+pub mod data;
+pub mod handle;
+pub mod inode;
+pub mod lock;
+pub mod quota;
+pub mod readahead;
+pub mod rstats;
+pub mod session;
+pub mod subdir;
+pub mod sync;
+pub mod xattr;
+
+pub static INODE_PARTITIONS:u64=5;
+
+//The root inode lives in partition 1, and lost+found is reserved at mkfs
+//time in partition 2 so that fsck always knows where to find it.
+pub static ROOT_INODE:u64=1;
+pub static LOST_FOUND_INODE:u64=2;
+
+//statfs block size. FileAttr.blocks is always in 512 byte units as well.
+pub static BLOCK_SIZE:u64=512;
+
+//Used by statfs when mkcrustfs wasn't given --capacity or --max-inodes.
+pub static DEFAULT_CAPACITY:u64=1 << 50;
+pub static DEFAULT_MAX_INODES:u64=1 << 32;
+
+/// (blocks, used blocks, free blocks, free inodes) for statfs. A partial
+/// block counts as used, and usage past the limits reports nothing free
+/// rather than wrapping.
+fn statfs_counts(capacity: u64, max_inodes: u64, bytes: u64, inodes: u64) -> (u64, u64, u64, u64) {
+    let blocks = capacity / BLOCK_SIZE;
+    let used = (bytes + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let bfree = if used > blocks {0} else {blocks - used};
+    let ffree = if inodes > max_inodes {0} else {max_inodes - inodes};
+    (blocks, used, bfree, ffree)
+}
+
+
+/// The inode allocate_inode hands out next in partition, given the largest
+/// one there. Inodes are partition + n * INODE_PARTITIONS; a partition
+/// mkcrustfs left empty starts at its first, and inode 0 is never used.
+fn next_inode(partition: u64, max_inode: Option<u64>) -> u64 {
+    match max_inode {
+        Some(max_inode) => max_inode + INODE_PARTITIONS,
+        None if partition == 0 => INODE_PARTITIONS,
+        None => partition,
+    }
+}
+
+/// Parse a byte count with an optional K, M, G or T suffix.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        Some('T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok().map(|n| n << shift)
+}
+
 
 pub struct Commands {
     pub use_ks:&'static str,
@@ -85,20 +140,135 @@ pub struct Commands {
     pub create_ks:&'static str,
     pub drop_inode_table:&'static str,
     pub drop_fs_metadata_table:&'static str,
+    pub drop_usage_table:&'static str,
+    pub drop_quota_usage_table:&'static str,
+    pub drop_quota_limits_table:&'static str,
     pub create_inode_table:&'static str,
     pub create_fs_metadata_table:&'static str,
+    pub create_usage_table:&'static str,
+    pub insert_fs_metadata:&'static str,
+    pub select_fs_metadata:&'static str,
+    pub update_usage:&'static str,
+    pub select_usage:&'static str,
+    pub create_quota_usage_table:&'static str,
+    pub create_quota_limits_table:&'static str,
+    pub update_quota_usage:&'static str,
+    pub select_quota_usage:&'static str,
+    pub select_all_quota_usage:&'static str,
+    pub insert_quota_limits:&'static str,
+    pub select_quota_limits:&'static str,
     pub create_inode:&'static str,
     pub select_max_inode:&'static str,
     pub insert_default_inode:&'static str,
     pub add_inode_to_parent:&'static str,
-    pub create_root_inode:&'static str,
+    pub link_new_inode:&'static str,
+    pub delete_inode:&'static str,
+    pub insert_dir_inode:&'static str,
     pub create_null_inode:&'static str,
     pub select_child_inodes:&'static str,
+    pub select_partition_inodes:&'static str,
+    pub remove_inode_from_parent:&'static str,
+    pub update_nlink:&'static str,
+    pub update_parent_inode:&'static str,
+    pub update_project:&'static str,
+    pub update_perm:&'static str,
+    pub update_owner:&'static str,
+    pub add_project_column:&'static str,
+    pub add_inline_column:&'static str,
+    pub add_nsec_columns:[&'static str; 4],
+    pub create_xattr_table:&'static str,
+    pub drop_xattr_table:&'static str,
+    pub select_xattr:&'static str,
+    pub select_xattr_names:&'static str,
+    pub insert_xattr:&'static str,
+    pub insert_xattr_if_not_exists:&'static str,
+    pub update_xattr_if_exists:&'static str,
+    pub delete_xattr:&'static str,
+    pub delete_xattrs:&'static str,
+    pub create_locks_table:&'static str,
+    pub drop_locks_table:&'static str,
+    pub select_locks:&'static str,
+    pub insert_locks:&'static str,
+    pub update_locks:&'static str,
+    pub create_sessions_table:&'static str,
+    pub drop_sessions_table:&'static str,
+    pub insert_session:&'static str,
+    pub delete_session:&'static str,
+    pub select_session:&'static str,
+    pub select_sessions:&'static str,
+    pub create_changes_table:&'static str,
+    pub drop_changes_table:&'static str,
+    pub drop_unsharded_changes_table:&'static str,
+    pub insert_change:&'static str,
+    pub select_changes:&'static str,
+    pub create_chunks_table:&'static str,
+    pub drop_chunks_table:&'static str,
+    pub select_chunk:&'static str,
+    pub insert_chunk:&'static str,
+    pub delete_chunk:&'static str,
+    pub create_chunk_map_table:&'static str,
+    pub drop_chunk_map_table:&'static str,
+    pub select_chunk_map:&'static str,
+    pub insert_chunk_map:&'static str,
+    pub delete_chunk_map:&'static str,
+    pub delete_chunk_maps:&'static str,
+    pub update_blocks:&'static str,
+    pub update_atime:&'static str,
+    pub update_mtime:&'static str,
+    pub update_ctime:&'static str,
+    pub update_size:&'static str,
+    pub update_inline:&'static str,
+    pub update_size_chunked:&'static str,
+    pub create_rstats_table:&'static str,
+    pub drop_rstats_table:&'static str,
+    pub update_rstats:&'static str,
+    pub select_rstats:&'static str,
+    pub create_rctimes_table:&'static str,
+    pub drop_rctimes_table:&'static str,
+    pub update_rctime:&'static str,
+    pub select_rctime:&'static str,
+    pub delete_placeholder_inode:&'static str,
+    pub delete_unlinked_inode:&'static str,
 }
 
 pub struct CrustFS {
     pub session:CassSession,
     pub cmds:Commands,
+    //background threads open their own connections with this
+    pub cluster_config:ClusterConfig,
+    //identifies this mount to other clients, e.g. as the holder of a lock
+    pub client_id:u64,
+    //inodes we hold locks on, shared with the heartbeat thread
+    pub held_locks:Arc<Mutex<HashSet<u64>>>,
+    //blocking setlk requests, shared with the lock waiter thread
+    pub lock_waiters:Arc<Mutex<Vec<LockWaiter>>>,
+    //our row in crustfs.sessions, once registered
+    pub session_info:Arc<Mutex<Option<SessionInfo>>>,
+    pub consistency:ConsistencyLevels,
+    //writes not waited for yet, by inode
+    pub pending:HashMap<u64, Pending>,
+    pub cache:AttrCache,
+    //other clients' changes, queued by the change log tail for the fuse loop
+    pub invalidations:Arc<Mutex<Vec<Change>>>,
+    pub write_buffers:HashMap<u64, WriteBuffer>,
+    //bytes held in all of write_buffers
+    pub buffered_bytes:usize,
+    //open files and directories, by fh
+    pub handles:HashMap<u64, Handle>,
+    pub next_fh:u64,
+    //ticks on every read, to find the least recently read handle
+    pub read_clock:u64,
+    //files up to this size keep their data in the inode row
+    pub inline_max:u64,
+    pub atime_policy:AtimePolicy,
+    //relatime updates not written yet, shared with the heartbeat thread
+    pub atimes:Arc<Mutex<Atimes>>,
+    //subtree statistics changes, see rstats.rs
+    pub rstat_changes:RStatChanges,
+    //the directory the kernel sees as the root of the mount, see subdir.rs
+    pub root:u64,
+    //refuse every change; only the session row and read locks are written
+    pub read_only:bool,
 }
 
 impl CrustFS {
@@ -109,43 +279,274 @@ impl CrustFS {
             WITH replication = {'class': 'SimpleStrategy', 'replication_factor': '1' };",
             drop_inode_table: "DROP TABLE IF EXISTS crustfs.inode",
             drop_fs_metadata_table: "DROP TABLE IF EXISTS crustfs.fs_metadata",
+            drop_usage_table: "DROP TABLE IF EXISTS crustfs.fs_usage",
+            drop_quota_usage_table: "DROP TABLE IF EXISTS crustfs.quota_usage",
+            drop_quota_limits_table: "DROP TABLE IF EXISTS crustfs.quota_limits",
             create_inode_table: "CREATE TABLE IF NOT EXISTS crustfs.inode
             (part_id bigint, inode bigint, parent_inode bigint, size bigint, blocks bigint,
             atime bigint, mtime bigint,ctime bigint, crtime bigint, kind text, perm int,
             nlink int, uid int, gid int, rdev int, flags int, dir_contents map<text,bigint>,
-            PRIMARY KEY (part_id,inode))
+            project bigint, inline_data blob, atime_nsec int, mtime_nsec int, ctime_nsec int,
+            crtime_nsec int, PRIMARY KEY (part_id,inode))
             WITH CLUSTERING ORDER BY (inode DESC);",
             create_fs_metadata_table: "CREATE TABLE IF NOT EXISTS crustfs.fs_metadata
             (key text, value text, PRIMARY KEY (key))",
+            create_usage_table: "CREATE TABLE IF NOT EXISTS crustfs.fs_usage
+            (key text, value counter, PRIMARY KEY (key))",
+            insert_fs_metadata: "INSERT INTO crustfs.fs_metadata (key, value) VALUES (?, ?)",
+            select_fs_metadata: "SELECT key, value FROM crustfs.fs_metadata",
+            update_usage: "UPDATE crustfs.fs_usage SET value = value + ? WHERE key = ?",
+            select_usage: "SELECT key, value FROM crustfs.fs_usage",
+            create_quota_usage_table: "CREATE TABLE IF NOT EXISTS crustfs.quota_usage
+            (kind text, id bigint, bytes counter, inodes counter, PRIMARY KEY (kind, id))",
+            create_quota_limits_table: "CREATE TABLE IF NOT EXISTS crustfs.quota_limits
+            (kind text, id bigint, bytes_soft bigint, bytes_hard bigint, inodes_soft bigint,
+            inodes_hard bigint, grace bigint, bytes_over_since bigint, inodes_over_since bigint,
+            PRIMARY KEY (kind, id))",
+            update_quota_usage: "UPDATE crustfs.quota_usage SET bytes = bytes + ?, inodes = inodes + ?
+            WHERE kind=? and id=?",
+            select_quota_usage: "SELECT bytes, inodes FROM crustfs.quota_usage WHERE kind=? and id=?",
+            select_all_quota_usage: "SELECT kind, id, bytes, inodes FROM crustfs.quota_usage",
+            insert_quota_limits: "INSERT INTO crustfs.quota_limits (kind, id, bytes_soft, bytes_hard,
+            inodes_soft, inodes_hard, grace, bytes_over_since, inodes_over_since) VALUES (?,?,?,?,?,?,?,?,?)",
+            select_quota_limits: "SELECT bytes_soft, bytes_hard, inodes_soft, inodes_hard, grace,
+            bytes_over_since, inodes_over_since FROM crustfs.quota_limits WHERE kind=? and id=?",
             select_inode: "SELECT part_id,inode,dir_contents,parent_inode,size,blocks,atime,
-            mtime,ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags FROM crustfs.inode
+            mtime,ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags,project,inline_data,
+            atime_nsec,mtime_nsec,ctime_nsec,crtime_nsec FROM crustfs.inode
             WHERE part_id=? and inode =?;",
             create_inode: "UPDATE crustfs.inode SET parent_inode=?, size=?, blocks=?,
-            atime=?, mtime=?, ctime=?, crtime=?, kind=?, perm=?, nlink=?, uid=?, gid=?, rdev=?, flags=?
+            atime=?, mtime=?, ctime=?, crtime=?, kind=?, perm=?, nlink=?, uid=?, gid=?, rdev=?, flags=?,
+            project=?, inline_data=?, atime_nsec=?, mtime_nsec=?, ctime_nsec=?, crtime_nsec=?
             where part_id = ? and inode = ? if parent_inode=NULL",
             add_inode_to_parent: "UPDATE crustfs.inode SET dir_contents[?] = ? WHERE part_id=? and inode=?
             IF kind='dir'",
-            insert_default_inode: "INSERT INTO crustfs.inode(part_id, inode, dir_contents)
-            VALUES(?,?,{}) IF NOT EXISTS",
+            link_new_inode: "UPDATE crustfs.inode SET dir_contents[?] = ? WHERE part_id=? and inode=?
+            IF kind='dir' AND dir_contents[?] = NULL",
+            delete_inode: "DELETE FROM crustfs.inode WHERE part_id=? and inode=?",
+            insert_default_inode: "INSERT INTO crustfs.inode(part_id, inode, crtime, dir_contents)
+            VALUES(?,?,?,{}) IF NOT EXISTS",
             select_max_inode: "SELECT inode FROM crustfs.inode where part_id = ? order by inode desc
             limit 1",
-            create_root_inode: "INSERT INTO crustfs.inode (part_id, inode, size, blocks, atime,mtime,
-            ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags)
-            VALUES(1,1,4096,1,?,?,?,?,'dir',0,0,0,0,0,0)",
+            insert_dir_inode: "INSERT INTO crustfs.inode (part_id, inode, parent_inode, size, blocks,
+            atime,mtime,ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags,dir_contents,
+            atime_nsec,mtime_nsec,ctime_nsec,crtime_nsec)
+            VALUES(?,?,?,0,0,?,?,?,?,'dir',?,?,?,?,0,0,{},?,?,?,?)",
             create_null_inode: "INSERT INTO crustfs.inode (part_id, inode, size, blocks, atime,mtime,
             ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags)
             VALUES(0,0,0,0,0,0,0,0,'null',0,0,0,0,0,0)",
             select_child_inodes: "SELECT dir_contents FROM crustfs.inode where part_id=? and inode=?",
+            select_partition_inodes: "SELECT part_id,inode,dir_contents,parent_inode,size,blocks,atime,
+            mtime,ctime,crtime,kind,perm,nlink,uid,gid,rdev,flags,project,inline_data,
+            atime_nsec,mtime_nsec,ctime_nsec,crtime_nsec FROM crustfs.inode
+            WHERE part_id=?;",
+            remove_inode_from_parent: "DELETE dir_contents[?] FROM crustfs.inode WHERE part_id=? and inode=?",
+            update_nlink: "UPDATE crustfs.inode SET nlink=? WHERE part_id=? and inode=?",
+            update_parent_inode: "UPDATE crustfs.inode SET parent_inode=? WHERE part_id=? and inode=?",
+            update_project: "UPDATE crustfs.inode SET project=? WHERE part_id=? and inode=?",
+            update_perm: "UPDATE crustfs.inode SET perm=?, ctime=?, ctime_nsec=? WHERE part_id=? and inode=?",
+            update_owner: "UPDATE crustfs.inode SET uid=?, gid=?, ctime=?, ctime_nsec=? WHERE part_id=? and inode=?",
+            add_project_column: "ALTER TABLE crustfs.inode ADD project bigint",
+            add_inline_column: "ALTER TABLE crustfs.inode ADD inline_data blob",
+            add_nsec_columns: ["ALTER TABLE crustfs.inode ADD atime_nsec int",
+                "ALTER TABLE crustfs.inode ADD mtime_nsec int",
+                "ALTER TABLE crustfs.inode ADD ctime_nsec int",
+                "ALTER TABLE crustfs.inode ADD crtime_nsec int"],
+            create_xattr_table: "CREATE TABLE IF NOT EXISTS crustfs.xattr
+            (inode bigint, name text, value blob, PRIMARY KEY (inode, name))",
+            drop_xattr_table: "DROP TABLE IF EXISTS crustfs.xattr",
+            select_xattr: "SELECT value FROM crustfs.xattr WHERE inode=? and name=?",
+            select_xattr_names: "SELECT name FROM crustfs.xattr WHERE inode=?",
+            insert_xattr: "INSERT INTO crustfs.xattr (inode, name, value) VALUES (?,?,?)",
+            insert_xattr_if_not_exists: "INSERT INTO crustfs.xattr (inode, name, value) VALUES (?,?,?)
+            IF NOT EXISTS",
+            update_xattr_if_exists: "UPDATE crustfs.xattr SET value=? WHERE inode=? and name=? IF EXISTS",
+            delete_xattr: "DELETE FROM crustfs.xattr WHERE inode=? and name=? IF EXISTS",
+            delete_xattrs: "DELETE FROM crustfs.xattr WHERE inode=?",
+            create_locks_table: "CREATE TABLE IF NOT EXISTS crustfs.locks
+            (inode bigint, version bigint, locks text, PRIMARY KEY (inode))",
+            drop_locks_table: "DROP TABLE IF EXISTS crustfs.locks",
+            select_locks: "SELECT version, locks FROM crustfs.locks WHERE inode=?",
+            insert_locks: "INSERT INTO crustfs.locks (inode, version, locks) VALUES (?, 0, ?) IF NOT EXISTS",
+            update_locks: "UPDATE crustfs.locks SET locks=?, version=? WHERE inode=? IF version=?",
+            create_sessions_table: "CREATE TABLE IF NOT EXISTS crustfs.sessions
+            (client_id bigint, host text, pid int, mounted bigint, version text, mountpoint text,
+            PRIMARY KEY (client_id))",
+            drop_sessions_table: "DROP TABLE IF EXISTS crustfs.sessions",
+            insert_session: "INSERT INTO crustfs.sessions (client_id, host, pid, mounted, version, mountpoint)
+            VALUES (?,?,?,?,?,?) USING TTL ?",
+            delete_session: "DELETE FROM crustfs.sessions WHERE client_id=?",
+            select_session: "SELECT client_id FROM crustfs.sessions WHERE client_id=?",
+            select_sessions: "SELECT client_id, host, pid, mounted, version, mountpoint FROM crustfs.sessions",
+            create_changes_table: "CREATE TABLE IF NOT EXISTS crustfs.change_log
+            (bucket bigint, shard int, ts bigint, id bigint, client bigint, inode bigint, parent bigint, name text,
+            PRIMARY KEY ((bucket, shard), ts, id))",
+            drop_changes_table: "DROP TABLE IF EXISTS crustfs.change_log",
+            //the log before it was sharded; its rows expire within the hour anyway
+            drop_unsharded_changes_table: "DROP TABLE IF EXISTS crustfs.changes",
+            insert_change: "INSERT INTO crustfs.change_log (bucket, shard, ts, id, client, inode, parent, name)
+            VALUES (?,?,?,?,?,?,?,?) USING TTL ?",
+            select_changes: "SELECT client, inode, parent, name FROM crustfs.change_log
+            WHERE bucket=? AND shard=? AND ts>=?",
+            create_chunks_table: "CREATE TABLE IF NOT EXISTS crustfs.chunks
+            (inode bigint, chunk bigint, data blob, PRIMARY KEY ((inode, chunk)))",
+            drop_chunks_table: "DROP TABLE IF EXISTS crustfs.chunks",
+            select_chunk: "SELECT data FROM crustfs.chunks WHERE inode=? AND chunk=?",
+            insert_chunk: "INSERT INTO crustfs.chunks (inode, chunk, data) VALUES (?,?,?)",
+            delete_chunk: "DELETE FROM crustfs.chunks WHERE inode=? AND chunk=?",
+            create_chunk_map_table: "CREATE TABLE IF NOT EXISTS crustfs.chunk_map
+            (inode bigint, chunk bigint, PRIMARY KEY (inode, chunk))",
+            drop_chunk_map_table: "DROP TABLE IF EXISTS crustfs.chunk_map",
+            select_chunk_map: "SELECT chunk FROM crustfs.chunk_map WHERE inode=? AND chunk>=? AND chunk<=?",
+            insert_chunk_map: "INSERT INTO crustfs.chunk_map (inode, chunk) VALUES (?,?)",
+            delete_chunk_map: "DELETE FROM crustfs.chunk_map WHERE inode=? AND chunk=?",
+            delete_chunk_maps: "DELETE FROM crustfs.chunk_map WHERE inode=?",
+            update_blocks: "UPDATE crustfs.inode SET blocks=? WHERE part_id=? AND inode=?",
+            update_atime: "UPDATE crustfs.inode SET atime=?, atime_nsec=? WHERE part_id=? AND inode=?",
+            update_mtime: "UPDATE crustfs.inode SET mtime=?, mtime_nsec=?, ctime=?, ctime_nsec=?
+            WHERE part_id=? AND inode=?",
+            update_ctime: "UPDATE crustfs.inode SET ctime=?, ctime_nsec=? WHERE part_id=? AND inode=?",
+            update_size: "UPDATE crustfs.inode SET size=?, blocks=?, mtime=?, mtime_nsec=?, ctime=?, ctime_nsec=?
+            WHERE part_id=? AND inode=?",
+            update_inline: "UPDATE crustfs.inode SET inline_data=?, size=?, blocks=?, mtime=?, mtime_nsec=?,
+            ctime=?, ctime_nsec=? WHERE part_id=? AND inode=?",
+            update_size_chunked: "UPDATE crustfs.inode SET inline_data=null, size=?, blocks=?, mtime=?, mtime_nsec=?,
+            ctime=?, ctime_nsec=? WHERE part_id=? AND inode=?",
+            create_rstats_table: "CREATE TABLE IF NOT EXISTS crustfs.rstats
+            (inode bigint, rbytes counter, rfiles counter, rsubdirs counter, PRIMARY KEY (inode))",
+            drop_rstats_table: "DROP TABLE IF EXISTS crustfs.rstats",
+            update_rstats: "UPDATE crustfs.rstats SET rbytes = rbytes + ?, rfiles = rfiles + ?,
+            rsubdirs = rsubdirs + ? WHERE inode=?",
+            select_rstats: "SELECT rbytes, rfiles, rsubdirs FROM crustfs.rstats WHERE inode=?",
+            create_rctimes_table: "CREATE TABLE IF NOT EXISTS crustfs.rctimes
+            (inode bigint, rctime bigint, rctime_nsec int, PRIMARY KEY (inode))",
+            drop_rctimes_table: "DROP TABLE IF EXISTS crustfs.rctimes",
+            update_rctime: "UPDATE crustfs.rctimes USING TIMESTAMP ? SET rctime=?, rctime_nsec=? WHERE inode=?",
+            select_rctime: "SELECT rctime, rctime_nsec FROM crustfs.rctimes WHERE inode=?",
+            delete_placeholder_inode: "DELETE FROM crustfs.inode WHERE part_id=? and inode=?
+            IF parent_inode=NULL",
+            //an empty inode create never linked, still where it would have gone
+            delete_unlinked_inode: "DELETE FROM crustfs.inode WHERE part_id=? and inode=?
+            IF parent_inode=? AND size=0",
         };
-        CrustFS{session:session,cmds:cmds}
+        CrustFS{session:session,cmds:cmds,
+            cluster_config:ClusterConfig::new(),
+            client_id:rand::thread_rng().gen(),
+            held_locks:lock::new_held_locks(),
+            lock_waiters:lock::new_lock_waiters(),
+            session_info:Arc::new(Mutex::new(None)),
+            consistency:ConsistencyLevels::new(),
+            pending:sync::new_pending(),
+            cache:AttrCache::new(cache::DEFAULT_ATTR_TTL, cache::DEFAULT_ENTRY_TTL, cache::DEFAULT_CACHE_SIZE),
+            invalidations:changelog::new_invalidations(),
+            write_buffers:data::new_write_buffers(),
+            buffered_bytes:0,
+            handles:handle::new_handles(),
+            next_fh:0,
+            read_clock:0,
+            inline_max:data::DEFAULT_INLINE_MAX,
+            atime_policy:AtimePolicy::RelAtime,
+            atimes:atime::new_atimes(),
+            rstat_changes:RStatChanges::new(),
+            root:ROOT_INODE,
+            read_only:false,
+        }
     }
 
   pub fn execute(&self, statement: String) -> ResultFuture {
         self.session.execute(statement.as_str(),0)
     }
 
+  /// For the admin tools, which mix reads and writes; runs at the metadata write level.
   pub fn execute_statement(&self, statement: CassStatement) -> ResultFuture {
-        self.session.execute_statement(&statement)
+        self.write_meta(&statement)
+    }
+
+    /// Adjust the fs wide usage counters that statfs reports. Every path that
+    /// allocates or frees inodes or file bytes (create, write, truncate, unlink)
+    /// has to call this with the delta it applied.
+    pub fn update_usage(&self, bytes: i64, inodes: i64) -> Result<(), CassError> {
+        for &(key, delta) in [("bytes", bytes), ("inodes", inodes)].iter() {
+            if delta == 0 {
+                continue;
+            }
+            let statement = CassStatement::new(self.cmds.update_usage, 2);
+            statement.bind_int64(0, delta).unwrap();
+            statement.bind_string(1, key).unwrap();
+            try!(self.write_meta(&statement).wait());
+        }
+        Ok(())
+    }
+
+    /// Returns (bytes, inodes) currently in use.
+    pub fn usage(&self) -> Result<(u64, u64), CassError> {
+        let statement = CassStatement::new(self.cmds.select_usage, 0);
+        let result = try!(self.read_meta(&statement).wait());
+        let (mut bytes, mut inodes) = (0, 0);
+        for row in result.iter() {
+            let value = row.get_column(1).unwrap().get_int64().unwrap();
+            match row.get_column(0).unwrap().get_string().unwrap().to_string().as_str() {
+                "bytes" => bytes = value,
+                "inodes" => inodes = value,
+                _ => {}
+            }
+        }
+        //counters can go negative if a delta got applied twice; don't let statfs wrap
+        Ok((if bytes < 0 {0} else {bytes as u64}, if inodes < 0 {0} else {inodes as u64}))
+    }
+
+    /// Create whatever tables are missing and bring older ones up to date, so
+    /// that a filesystem made by an older mkcrustfs can be used. The mount and
+    /// every admin tool that reads inodes call this first.
+    pub fn upgrade_schema(&self) -> Result<(), CassError> {
+        try!(self.execute(self.cmds.create_ks.to_string()).wait());
+        try!(self.execute(self.cmds.create_inode_table.to_string()).wait());
+        try!(self.execute(self.cmds.create_fs_metadata_table.to_string()).wait());
+        //filesystems made before project quotas existed lack the column; this fails harmlessly on newer ones
+        let _ = self.execute(self.cmds.add_project_column.to_string()).wait();
+        //likewise for inline data; files on such filesystems stay in chunks
+        let _ = self.execute(self.cmds.add_inline_column.to_string()).wait();
+        //and for the nanosecond parts of timestamps; older rows read as whole seconds
+        for cmd in self.cmds.add_nsec_columns.iter() {
+            let _ = self.execute(cmd.to_string()).wait();
+        }
+        for cmd in [self.cmds.create_usage_table, self.cmds.create_quota_usage_table,
+                    self.cmds.create_quota_limits_table, self.cmds.create_xattr_table,
+                    self.cmds.create_locks_table, self.cmds.create_sessions_table,
+                    self.cmds.create_changes_table, self.cmds.create_chunks_table,
+                    self.cmds.create_chunk_map_table, self.cmds.create_rstats_table,
+                    self.cmds.create_rctimes_table].iter() {
+            try!(self.execute(cmd.to_string()).wait());
+        }
+        Ok(())
+    }
+
+    /// Look up a u64 setting in crustfs.fs_metadata.
+    pub fn fs_metadata_u64(&self, key: &str) -> Result<Option<u64>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_fs_metadata, 0);
+        let result = try!(self.read_meta(&statement).wait());
+        for row in result.iter() {
+            if row.get_column(0).unwrap().get_string().unwrap().to_string() == key {
+                return Ok(row.get_column(1).unwrap().get_string().unwrap().to_string().parse().ok());
+            }
+        }
+        Ok(None)
+    }
+
+    /// Run a lightweight transaction and report whether it was applied.
+    /// Statements without an IF clause come back with no rows and count as applied.
+    pub fn execute_lwt(&self, statement: CassStatement) -> Result<bool, CassError> {
+        statement.set_serial_consistency(self.consistency.serial).unwrap();
+        let result = try!(self.write_meta(&statement).wait());
+        Ok(result.first_row().map(|row| row.get_column(0).unwrap().get_bool().unwrap()).unwrap_or(true))
+    }
+
+    /// Read every row (including placeholders) of one inode partition.
+    pub fn select_partition(&self, partition: u64) -> Result<Vec<InodeRow>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_partition_inodes, 1);
+        statement.bind_int64(0, partition as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.iter().map(|row| InodeRow::from_row(&row)).collect())
     }
 
 
@@ -160,7 +561,7 @@ impl CrustFS {
     and inserts a stub (just the partition_id and inode)
     reserving the inode for the calling function
     */
-    fn allocate_inode(&mut self) -> (u64, u64) {
+    fn allocate_inode(&mut self) -> Result<(u64, u64), c_int> {
         debug!("allocate_inode");
         //choose a random partition
         let partition:u64 = rand::thread_rng().gen_range(0u64,INODE_PARTITIONS);
@@ -169,123 +570,292 @@ impl CrustFS {
         let select_max_inode_statement = CassStatement::new(self.cmds.select_max_inode, 1);
         debug!("allocate_inode: binding partition: {}",partition);
         select_max_inode_statement.bind_int64(0, partition as i64).unwrap();
-
-        //return the inode that is generated out of the
-        let mut future = self.session.execute_statement(&select_max_inode_statement);
-        //future.wait();
-        //FIXME match not needed or safe api should change. choose.
-        match future.wait() {
-            Err(err) => panic!("{:?}",err),
-            Ok(select_result) => {
-                //generate  a new inode by taking max found in #2 + INODE_PARTITIONS
-                //which is our offset for inodes within each partition.
-                let next_inode = match select_result.first_row() {
-                    None => {panic!("no first row")},
-                    Some(row) => {
-                        match row.get_column(0).unwrap().get_int64() {
-                            Ok(res) => {
-                                debug!("allocate_inode: got row {} in partition {}. adding new row {}",
-                                res,partition,res as u64+INODE_PARTITIONS);
-                                res as u64 + INODE_PARTITIONS
-                            },
-                            Err(e) => {panic!("corrupt fs: {:?}",e)}
-                        }
-                    }
-                };
-                //insert into inode if not exists on the new inode.
-                let insert_inode_placeholder_stmt = CassStatement::new(self.cmds.insert_default_inode,2);
-
-                //FIXME make these chainable
-                insert_inode_placeholder_stmt.bind_int64(0, partition as i64).unwrap();
-                insert_inode_placeholder_stmt.bind_int64(1, next_inode as i64).unwrap();
-                let mut future = self.session.execute_statement(&insert_inode_placeholder_stmt);
-                match future.wait() {
-                    Ok(_) => {
-                        //FIXME. make sure I don't need to pay more attention to a succsesful result
-                        //the insert succeeded, so we can consider the generated inode to be valid
-                        (partition,next_inode)
-                    }
-                    Err(err) => {
-                        debug!("allocate_inode: insert race condition encountered: {:?}", err);
-                        self.allocate_inode() //can retry on failed inode allocation be tail recursive?
-                    }
+        let select_result = match self.read_meta(&select_max_inode_statement).wait() {
+            Ok(select_result) => select_result,
+            Err(err) => {
+                error!("allocate_inode: unable to read partition {}: {:?}", partition, err);
+                return Err(EIO);
+            }
+        };
+        let max_inode = match select_result.first_row() {
+            None => None,
+            Some(row) => match row.get_column(0).unwrap().get_int64() {
+                Ok(res) => Some(res as u64),
+                Err(err) => {
+                    error!("allocate_inode: corrupt inode in partition {}: {:?}", partition, err);
+                    return Err(EIO);
                 }
+            }
+        };
+        let next_inode = next_inode(partition, max_inode);
+        debug!("allocate_inode: max {:?} in partition {}. adding new row {}", max_inode, partition, next_inode);
+
+        //insert into inode if not exists on the new inode.
+        //crtime lets crustfs-gc tell an abandoned placeholder from one that create is still filling in
+        let insert_inode_placeholder_stmt = CassStatement::new(self.cmds.insert_default_inode,3);
+        insert_inode_placeholder_stmt.bind_int64(0, partition as i64).unwrap();
+        insert_inode_placeholder_stmt.bind_int64(1, next_inode as i64).unwrap();
+        insert_inode_placeholder_stmt.bind_int64(2, time::get_time().sec as i64).unwrap();
+        match self.execute_lwt(insert_inode_placeholder_stmt) {
+            //the generated inode is ours
+            Ok(true) => Ok((partition,next_inode)),
+            Ok(false) => {
+                debug!("allocate_inode: inode {} was taken by another client, retrying", next_inode);
+                self.allocate_inode()
             },
+            Err(err) => {
+                error!("allocate_inode: unable to reserve inode {}: {:?}", next_inode, err);
+                Err(EIO)
+            }
         }
     }
-}
 
-fn inode_exists(result:CassResult, name: &Path) -> bool {
-    match result.first_row() {
-        None=> true,
-        Some(row) => {
-            debug!("lookup: got row");
-            let mut exists=false;
-            match row.get_column(2).unwrap().set_iter() {
-                Err(err) => {panic!("bad: {:?}", err)}
-                Ok(iter) => {
-                    for value in iter {
-                        match value.is_null() {
-                            false =>  if value.get_string().unwrap() == name.to_str().unwrap() {exists = true} else {exists = false},
-                            true => {                        
-                                exists = true;
-                                debug!("file_exists");
-                            }
-                        }
-                        
-                    }
-                }
-            }
-            exists
-        }
+    pub fn get_inode(&self, ino: u64) -> Result<Option<InodeRow>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_inode, 2);
+        statement.bind_int64(0, (ino % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(1, ino as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.first_row().map(|row| InodeRow::from_row(&row)))
     }
-}
 
-struct Inode {
-    inode:u64
-}
+    fn dir_times_statement(&self, dir: u64) -> CassStatement {
+        let now = time::get_time();
+        let statement = CassStatement::new(self.cmds.update_mtime, 6);
+        statement.bind_int64(0, now.sec).unwrap();
+        statement.bind_int32(1, now.nsec).unwrap();
+        statement.bind_int64(2, now.sec).unwrap();
+        statement.bind_int32(3, now.nsec).unwrap();
+        statement.bind_int64(4, (dir % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(5, dir as i64).unwrap();
+        statement
+    }
+
+    /// An entry of dir was added, removed or renamed: move its mtime and ctime.
+    /// Its size follows from dir_contents by itself.
+    pub fn dir_changed(&mut self, dir: u64) {
+        let statement = self.dir_times_statement(dir);
+        //fsyncdir waits for it along with anything else queued for dir
+        self.queue_write(dir, OpClass::MetaWrite, statement);
+    }
 
-#[allow(dead_code)]
-impl Inode {
-    fn to_u64(&self) -> u64 {
-        self.inode
+    /// dir_changed for the admin tools, which wait for it right away.
+    pub fn touch_dir(&self, dir: u64) -> Result<(), CassError> {
+        try!(self.write_meta(&self.dir_times_statement(dir)).wait());
+        Ok(())
     }
 
-    fn to_i64(&self) -> i64 {
-        self.inode as i64
+    /// Walk an absolute path down from the root, one dir_contents map at a time.
+    pub fn resolve_path(&self, path: &str) -> Result<Option<u64>, CassError> {
+        let mut ino = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let row = match try!(self.get_inode(ino)) {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            ino = match row.dir_contents.get(name) {
+                Some(child) => *child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(ino))
     }
 
-    fn get_partition(&self) -> u64 {
-        self.inode % INODE_PARTITIONS
+    /// Delete an inode that was filled in but lost the race to be linked.
+    fn discard_inode(&self, partition: u64, inode: u64) {
+        let statement = CassStatement::new(self.cmds.delete_inode, 2);
+        statement.bind_int64(0, partition as i64).unwrap();
+        statement.bind_int64(1, inode as i64).unwrap();
+        if let Err(err) = self.write_meta(&statement).wait() {
+            //unlinked, so fsck will offer it to lost+found
+            error!("make_inode: unable to delete unlinked inode {}: {:?}", inode, err);
+        }
+    }
+
+    /// Reserve an inode, fill it in and link it into parent as name.
+    /// Shared by create and mkdir.
+    fn make_inode(&mut self, req: &Request, parent: u64, name: &Path, kind: FileType, mode: u32)
+                  -> Result<FileAttr, c_int> {
+        let path = match name.to_str() {
+            Some(path) => path,
+            None => return Err(EIO),
+        };
+        let parent_row = match self.get_inode(parent) {
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(ENOENT),
+            Err(err) => {
+                error!("make_inode: unable to read parent {}: {:?}", parent, err);
+                return Err(EIO);
+            }
+        };
+        if !parent_row.is_dir() {
+            return Err(ENOTDIR);
+        }
+        if parent_row.dir_contents.contains_key(path) {
+            return Err(EEXIST);
+        }
+        //new inodes land in their parent's project, if it has one
+        let (uid, gid, project) = (req.uid(), req.gid(), parent_row.project);
+        if !try!(self.check_access(&parent_row, uid, gid, W_OK | X_OK)) {
+            return Err(EACCES);
+        }
+        try!(self.check_quota(uid, gid, project, 0, 1));
+
+        //a default ACL on the parent replaces the umask, see acl(5)
+        let default_acl = match self.select_xattr(parent, ACL_DEFAULT) {
+            Ok(default_acl) => default_acl.and_then(|bytes| Acl::parse(&bytes).ok()),
+            Err(err) => {
+                error!("make_inode: unable to read default ACL of {}: {:?}", parent, err);
+                return Err(EIO);
+            }
+        };
+        let access_acl = default_acl.as_ref().map(|acl| acl.inherit(mode));
+        let mode = match access_acl {
+            Some(ref acl) => (mode & 0o7000) | acl.mode(),
+            None => mode,
+        };
+
+        let (partition,inode) = try!(self.allocate_inode());
+        let now = time::get_time();
+        let (kind_name, size, nlink) = match kind {
+            FileType::Directory => ("dir", dir_size(&BTreeMap::new()), 2),
+            _ => ("file", 0, 1),
+        };
+        let blocks = (size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        let new_inode = FileAttr{
+            ino:inode,
+            size:size,blocks:blocks,
+            atime:now,mtime:now,ctime:now,crtime:now,
+            kind:kind,
+            perm:(mode & 0o7777) as u16,
+            nlink:nlink,
+            uid:uid,gid:gid,
+            rdev:0,
+            flags:0,
+        };
+
+        let statement = CassStatement::new(self.cmds.create_inode, 22);
+        debug!("make_inode: inserting inode:{}",new_inode.ino);
+        statement.bind_int64(0, parent as i64).unwrap();
+        statement.bind_int64(1, new_inode.size as i64).unwrap();
+        statement.bind_int64(2, new_inode.blocks as i64).unwrap();
+        statement.bind_int64(3, new_inode.atime.sec as i64).unwrap();
+        statement.bind_int64(4, new_inode.mtime.sec as i64).unwrap();
+        statement.bind_int64(5, new_inode.ctime.sec as i64).unwrap();
+        statement.bind_int64(6, new_inode.crtime.sec as i64).unwrap();
+        statement.bind_string(7, kind_name).unwrap();
+        statement.bind_int32(8, new_inode.perm as i32).unwrap();
+        statement.bind_int32(9, new_inode.nlink as i32).unwrap();
+        statement.bind_int32(10, new_inode.uid as i32).unwrap();
+        statement.bind_int32(11, new_inode.gid as i32).unwrap();
+        statement.bind_int32(12, new_inode.rdev as i32).unwrap();
+        statement.bind_int32(13, new_inode.flags as i32).unwrap();
+        statement.bind_int64(14, project as i64).unwrap();
+        //new files start out inline, see data.rs
+        statement.bind_bytes(15, &[]).unwrap();
+        statement.bind_int32(16, new_inode.atime.nsec).unwrap();
+        statement.bind_int32(17, new_inode.mtime.nsec).unwrap();
+        statement.bind_int32(18, new_inode.ctime.nsec).unwrap();
+        statement.bind_int32(19, new_inode.crtime.nsec).unwrap();
+        statement.bind_int64(20, partition as i64).unwrap();
+        statement.bind_int64(21, new_inode.ino as i64).unwrap();
+        match self.execute_lwt(statement) {
+            Ok(true) => {},
+            //fsck or crustfs-gc took our placeholder for abandoned and deleted it
+            Ok(false) => {
+                error!("make_inode: placeholder for inode {} is gone", inode);
+                return Err(EIO);
+            },
+            Err(err) => {
+                //the placeholder is left for crustfs-gc
+                error!("make_inode: unable to fill in inode {}: {:?}", inode, err);
+                return Err(EIO);
+            }
+        }
+
+        //only one of two clients creating the same name may link it
+        let statement = CassStatement::new(self.cmds.link_new_inode, 5);
+        debug!("make_inode: adding inode {} to parent {}",new_inode.ino,parent);
+        statement.bind_string(0, path).unwrap();
+        statement.bind_int64(1, inode as i64).unwrap();
+        statement.bind_int64(2, (parent % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(3, parent as i64).unwrap();
+        statement.bind_string(4, path).unwrap();
+        match self.execute_lwt(statement) {
+            Ok(true) => {},
+            Ok(false) => {
+                self.discard_inode(partition, inode);
+                self.cache.invalidate_attr(parent);
+                return Err(EEXIST);
+            },
+            Err(err) => {
+                error!("make_inode: unable to link inode {} into {}: {:?}", inode, parent, err);
+                return Err(EIO);
+            }
+        }
+        self.dir_changed(parent);
+        let (files, subdirs) = if kind == FileType::Directory {(0, 1)} else {(1, 0)};
+        self.note_rstats(inode, 0, files, subdirs, now);
+
+        //the parent's nlink and our negative entry for name are both stale now
+        self.cache.invalidate_attr(parent);
+        self.cache.insert_entry(parent, path, Some(inode));
+        self.cache.insert_attr(&new_inode);
+        self.cache.insert_inline(inode, Some(Vec::new()));
+        self.log_change(inode, Some((parent, path)));
+
+        if let Some(ref acl) = access_acl {
+            let mut inherited = self.store_xattr(inode, ACL_ACCESS, &acl.to_bytes());
+            if kind == FileType::Directory {
+                inherited = inherited.and(self.store_xattr(inode, ACL_DEFAULT, &default_acl.as_ref().unwrap().to_bytes()));
+            }
+            if let Err(err) = inherited {
+                error!("make_inode: unable to inherit ACLs of {}: {:?}", parent, err);
+                return Err(EIO);
+            }
+        }
+
+        //the new directory's ".." links back to the parent
+        if kind == FileType::Directory {
+            let statement = CassStatement::new(self.cmds.update_nlink, 3);
+            statement.bind_int32(0, (parent_row.nlink + 1) as i32).unwrap();
+            statement.bind_int64(1, parent_row.part_id as i64).unwrap();
+            statement.bind_int64(2, parent as i64).unwrap();
+            if let Err(err) = self.write_meta(&statement).wait() {
+                error!("make_inode: unable to update nlink of {}: {:?}", parent, err);
+            }
+        }
+
+        if let Err(err) = self.update_usage(0, 1) {
+            error!("make_inode: unable to update usage: {:?}", err);
+        }
+        if let Err(err) = self.update_quota_usage(uid, gid, project, 0, 1) {
+            error!("make_inode: unable to update quota usage: {:?}", err);
+        }
+        Ok(new_inode)
     }
 }
 
 impl Filesystem for CrustFS {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &Path, reply: ReplyEntry) {
-        //let parent = Inode{inode:parent};
-        debug!("lookup: parent: {:?}, name: {:?}", parent, name.to_str().unwrap());
-        let statement = CassStatement:: new(self.cmds.select_inode, 2);
-        statement.bind_int64(0, parent as i64).unwrap();
-        statement.bind_int64(1,  Inode{inode:parent}.get_partition() as i64).unwrap();
-        match self.session.execute_statement(&statement).wait() {
-            Err(err) => debug!("lookup: {:?}", err),
-            Ok(result) => {
-                match inode_exists(result, name) {
-                    //FIXME build a proper ATTR struct here
-                    true => reply.entry(&TTL, &HELLO_TXT_ATTR, 0),
-                    false => reply.error(ENOENT)
-                }
-            }
+        debug!("lookup: parent: {:?}, name: {:?}", parent, name.to_str());
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return reply.error(ENOENT),
+        };
+        let parent = self.inner(parent);
+        let found = if name == ".." {self.parent_dir(parent)} else {self.lookup_child(parent, name)};
+        match found.and_then(|ino| self.cached_attr(ino)) {
+            Ok(attr) => reply.entry(&self.cache.entry_ttl, &self.outer_attr(&attr), 0),
+            Err(err) => reply.error(err),
         }
     }
-    
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        debug!("getattr");
-        match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
+        debug!("getattr: ino: {}", ino);
+        let ino = self.inner(ino);
+        match self.cached_attr(ino) {
+            Ok(attr) => reply.attr(&self.cache.attr_ttl, &self.outer_attr(&attr)),
+            Err(err) => reply.error(err),
         }
     }
 
@@ -296,37 +866,23 @@ impl Filesystem for CrustFS {
     /// return value of the read system call will reflect the return value of this
     /// operation. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value.
-    #[allow(unused_variables)]
     fn read(&mut self,
             _req: &Request,
             ino: u64,
             _fh: u64,
             offset: u64,
-            u32: u32,
+            size: u32,
             reply: ReplyData) {
-        let statement = CassStatement::new(self.cmds.select_inode.clone(), 1);
-
-        let mut future=self.session.execute_statement(&statement);
-        statement.bind_int64(0, ino as i64).unwrap();
-        match future.wait() {
-            Err(err) => {
-                reply.error(ENOENT)
-            },
-            Ok(result) => {
-                match result.first_row() {
-                    None => error!("--"),
-                    Some(row) => {
-                        match row.get_column(9).unwrap().get_string() {
-                            Err(err) => error!("{:?}--",err),
-                            Ok(col) => {
-                                //let cstr = CString::new(col.cass_string.data,false);
-                                println!("str: {:?}",col);
-                                reply.data(&col.to_string().as_bytes()[0..offset as usize]);
-                            }
-                        }
-                    }
+        debug!("read: ino: {}, offset: {}, size: {}", ino, offset, size);
+        let ino = self.inner(ino);
+        match self.read_range(_fh, ino, offset, size) {
+            Ok(data) => {
+                if let Ok(attr) = self.cached_attr(ino) {
+                    self.touch_atime(&attr);
                 }
-            }
+                reply.data(&data);
+            },
+            Err(err) => reply.error(err),
         }
     }
 
@@ -338,6 +894,9 @@ impl Filesystem for CrustFS {
              _mode: u32,
              _rdev: u32,
              reply: ReplyEntry) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOENT);
         panic!("mknod not implemented");
     }
@@ -348,8 +907,15 @@ impl Filesystem for CrustFS {
              _name: &Path,
              _mode: u32,
              reply: ReplyEntry) {
-        reply.error(ENOENT);
-        panic!("mkdir not implemented");
+        debug!("mkdir: parent: {}, name: {:?}, mode: {:o}", _parent, _name.to_str(), _mode);
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        let _parent = self.inner(_parent);
+        match self.make_inode(_req, _parent, _name, FileType::Directory, _mode) {
+            Ok(attr) => reply.entry(&self.cache.entry_ttl, &self.outer_attr(&attr), 0),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Read directory
@@ -363,54 +929,42 @@ impl Filesystem for CrustFS {
                _fh: u64,
                offset: u64,
                mut reply: ReplyDirectory) {
-        match offset {
-            0 => {
-                debug!("readdir ino:{} offset:{}",ino,offset);
-                let inode:Inode=Inode{inode:ino};
-                reply.add(ino, 1, FileType::Directory, &Path::new("."));
-                reply.add(ino, 1, FileType::Directory, &Path::new(".."));
-
-                let statement = CassStatement::new(self.cmds.select_child_inodes, 2);
-                statement.bind_int64(0, inode.to_i64()).unwrap();
-                statement.bind_int64(1, (inode.get_partition()) as i64).unwrap();
-                debug!("ino: {}, offset: {}", ino, offset);
-                let mut future = self.session.execute_statement(&statement);
-                match future.wait() {
-                    Err(err) => panic!("fail: {:?}", err),
-                    Ok(result) => {
-                        for row in result.iter() {
-//              let row = row_iterator.get_row();
-            //if row.length() > 0 {
-                            let column = row.get_column(0);
-                            let collection_items = column.unwrap().set_iter().unwrap();
-                            for item in collection_items {
-                                //let item = collection_items.get_column();
-                                match item.get_type() {
-                                    CassValueType::TEXT => {
-                                        let result = item.get_string();
-                                        panic!("result: {:?}", result);
-                                        //FIXME have to reply
-                                        //reply.add(ino, 1, FileType::RegularFile, &Path::new(result));
-                                        //debug!("readdir: item2: {:?}", result)
-                                    }
-                                    _ => panic!("getting an item from a collection should never fail")
-                                }
-                            }
-                        }
-                        debug!("readdir: ok reply");
-                        reply.ok();
-                    }
+        debug!("readdir: ino: {}, fh: {}, offset: {}", ino, _fh, offset);
+        let ino = self.inner(ino);
+        let entries = match self.handles.get(&_fh).and_then(|handle| handle.entries.as_ref()) {
+            Some(entries) => entries.iter().skip(offset as usize).cloned().collect::<Vec<(u64, String)>>(),
+            None => return reply.error(EINVAL),
+        };
+        if offset == 0 {
+            if let Ok(attr) = self.cached_attr(ino) {
+                self.touch_atime(&attr);
+            }
+        }
+        for (i, (child, name)) in entries.into_iter().enumerate() {
+            let kind = if name == "." || name == ".." {
+                FileType::Directory
+            } else {
+                match self.cached_attr(child) {
+                    Ok(attr) => attr.kind,
+                    //removed since opendir, the snapshot still lists it
+                    Err(ENOENT) => FileType::RegularFile,
+                    Err(err) => return reply.error(err),
                 }
-            },
-            _ => {
-                reply.error(ENOENT);
-                debug!("readdir: enonent reply");
+            };
+            //the offset of an entry is where the next readdir picks up
+            if reply.add(self.outer(child), offset + i as u64 + 1, kind, &Path::new(name.as_str())) {
+                break;
             }
         }
+        reply.ok();
     }
 
     fn init(&mut self, _req: &Request) -> Result<(), c_int> {
-        debug!("init");
+        debug!("init: client {}", self.client_id);
+        debug!("init: consistency {:?}", self.consistency);
+        self.start_heartbeat();
+        self.start_lock_waiter();
+        self.start_change_tail();
         Ok(())
     }
 
@@ -418,6 +972,13 @@ impl Filesystem for CrustFS {
     /// Called on filesystem exit.
     fn destroy(&mut self, _req: &Request) {
         debug!("destroy");
+        self.flush_all_buffers();
+        self.flush_atimes();
+        self.flush_rstats();
+        self.sync_all();
+        if let Err(err) = self.unregister_session() {
+            error!("destroy: unable to unregister session: {:?}", err);
+        }
     }
 
     /// Forget about an inode
@@ -428,7 +989,8 @@ impl Filesystem for CrustFS {
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
     /// inodes will receive a forget message.
     fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
-        panic!("forget not implemented");
+        //lookup counts aren't tracked, nothing is pinned by the kernel's references
+        debug!("forget: ino: {}, nlookup: {}", _ino, _nlookup);
     }
 
     /// Set file attributes
@@ -449,7 +1011,74 @@ impl Filesystem for CrustFS {
                _flags: Option<u32>,
                reply: ReplyAttr) {
         debug!("setattr");
-        reply.attr(&time::get_time(),&HELLO_TXT_ATTR);
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        //the macOS-only times and flags aren't supported; better to fail than to
+        //report a change that didn't happen
+        if _crtime.is_some() || _chgtime.is_some() || _bkuptime.is_some() || _flags.is_some() {
+            return reply.error(ENOSYS);
+        }
+        let _ino = self.inner(_ino);
+        //the mount doesn't use default_permissions, so the checks are ours
+        if _mode.is_some() || _uid.is_some() || _gid.is_some() || _size.is_some() || _atime.is_some() || _mtime.is_some() {
+            //chown moves the blocks buffered writes will take, so the row has to have them
+            if _uid.is_some() || _gid.is_some() {
+                self.flush_buffer(_ino);
+                self.wait_writes(_ino);
+            }
+            let row = match self.get_inode(_ino) {
+                Ok(Some(ref row)) if row.is_placeholder() => return reply.error(ENOENT),
+                Ok(Some(row)) => row,
+                Ok(None) => return reply.error(ENOENT),
+                Err(err) => {
+                    error!("setattr: unable to read inode {}: {:?}", _ino, err);
+                    return reply.error(EIO);
+                }
+            };
+            let (uid, gid) = (_req.uid(), _req.gid());
+            let mut allowed = Ok(());
+            if _mode.is_some() && !owns(&row, uid) {
+                allowed = Err(EPERM);
+            }
+            if (_uid.is_some() || _gid.is_some()) && !may_chown(row.uid, row.gid, uid, _uid, _gid) {
+                allowed = Err(EPERM);
+            }
+            if _size.is_some() {
+                allowed = allowed.and_then(|_| self.check_truncate(&row, uid, gid, _fh));
+            }
+            if _atime.is_some() || _mtime.is_some() {
+                let touch = only_now(_atime, _mtime, time::get_time());
+                allowed = allowed.and_then(|_| self.check_set_times(&row, uid, gid, touch));
+            }
+            if let Err(err) = allowed {
+                return reply.error(err);
+            }
+            if _uid.is_some() || _gid.is_some() {
+                if let Err(err) = self.change_owner(&row, _uid, _gid) {
+                    return reply.error(err);
+                }
+            }
+            if let Some(mode) = _mode {
+                let perm = chmod_mode(&row, uid, gid, mode);
+                if let Err(err) = self.change_mode(&row, perm) {
+                    return reply.error(err);
+                }
+            }
+        }
+        if let Some(size) = _size {
+            if let Err(err) = self.truncate(_ino, size) {
+                reply.error(err);
+                return;
+            }
+        }
+        if _atime.is_some() || _mtime.is_some() {
+            self.set_times(_ino, _atime, _mtime);
+        }
+        match self.cached_attr(_ino) {
+            Ok(attr) => reply.attr(&self.cache.attr_ttl, &self.outer_attr(&attr)),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Read symbolic link
@@ -460,12 +1089,18 @@ impl Filesystem for CrustFS {
 
     /// Remove a file
     fn unlink(&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOSYS);
         panic!("unlink not implemented");
     }
 
     /// Remove a directory
     fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &Path, reply: ReplyEmpty) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOSYS);
         panic!("rmdir not implemented");
     }
@@ -477,6 +1112,9 @@ impl Filesystem for CrustFS {
                _name: &Path,
                _link: &Path,
                reply: ReplyEntry) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOSYS);
         panic!("symlink not implemented: parent={:?}, name={:?}, link={:?}",
             _parent, _name.to_str(), _link.to_str());
@@ -491,6 +1129,9 @@ impl Filesystem for CrustFS {
               _newparent: u64,
               _newname: &Path,
               reply: ReplyEmpty) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOSYS);
         panic!("rename not implemented");
     }
@@ -502,6 +1143,9 @@ impl Filesystem for CrustFS {
             _newparent: u64,
             _newname: &Path,
             reply: ReplyEntry) {
+        if self.read_only {
+            return reply.error(EROFS);
+        }
         reply.error(ENOSYS);
         panic!("link not implemented: ino={:?}, _newparent={:?}, _newname={:?}",
             _ino, _newparent, _newname.to_str());
@@ -516,8 +1160,15 @@ impl Filesystem for CrustFS {
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
     fn open(&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        debug!("open");
-        reply.opened(0, 0);
+        debug!("open: ino: {}, flags: {:o}", _ino, _flags);
+        let _ino = self.inner(_ino);
+        match self.open_file(_req.uid(), _req.gid(), _ino, _flags) {
+            Ok(fh) => {
+                let flags = if self.handles[&fh].direct_io() {FOPEN_DIRECT_IO} else {0};
+                reply.opened(fh, flags);
+            },
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Write data
@@ -534,8 +1185,17 @@ impl Filesystem for CrustFS {
              _data: &[u8],
              _flags: u32,
              reply: ReplyWrite) {
-        reply.error(ENOSYS);
-        panic!("write not implemented");
+        debug!("write: ino: {}, offset: {}, size: {}", _ino, _offset, _data.len());
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        let _ino = self.inner(_ino);
+        //the kernel's idea of EOF may be out of date, ours isn't
+        let append = self.handles.get(&_fh).map(|handle| handle.append()).unwrap_or(false);
+        match self.buffer_write(_ino, _offset, _data, append) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Flush method
@@ -550,7 +1210,14 @@ impl Filesystem for CrustFS {
     /// operations (setlk, getlk) it should remove all locks belonging to 'lock_owner'.
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         debug!("flush");
-        reply.error(ENOSYS);
+        let _ino = self.inner(_ino);
+        //a write that failed after we replied to it is reported here, as close() expects
+        self.flush_buffer(_ino);
+        let synced = self.sync_inode(_ino, false);
+        match self.release_locks(_ino, _lock_owner).and(synced) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Release an open file
@@ -569,7 +1236,19 @@ impl Filesystem for CrustFS {
                _lock_owner: u64,
                _flush: bool,
                reply: ReplyEmpty) {
-        debug!("release");
+        debug!("release: ino: {}, fh: {}", _ino, _fh);
+        let _ino = self.inner(_ino);
+        self.release_handle(_fh);
+        //nobody is left to report an error to
+        self.flush_buffer(_ino);
+        if let Err(err) = self.sync_inode(_ino, false) {
+            error!("release: inode {} lost writes: {}", _ino, err);
+        }
+        if _flush {
+            if let Err(err) = self.release_locks(_ino, _lock_owner) {
+                return reply.error(err);
+            }
+        }
         reply.ok();
     }
 
@@ -577,8 +1256,13 @@ impl Filesystem for CrustFS {
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
-        panic!("fsync not implemented");
+        debug!("fsync: ino: {}, datasync: {}", _ino, _datasync);
+        let _ino = self.inner(_ino);
+        self.flush_buffer(_ino);
+        match self.sync_inode(_ino, _datasync) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Open a directory
@@ -589,8 +1273,12 @@ impl Filesystem for CrustFS {
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
     fn opendir(&mut self, _req: &Request, _ino: u64, _flags: u32, reply: ReplyOpen) {
-        debug!("opendir");
-        reply.opened(0, 0);
+        debug!("opendir: ino: {}", _ino);
+        let _ino = self.inner(_ino);
+        match self.open_dir(_req.uid(), _req.gid(), _ino, _flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Release an open directory
@@ -598,7 +1286,8 @@ impl Filesystem for CrustFS {
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
     fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
-        debug!("releasedir");
+        debug!("releasedir: ino: {}, fh: {}", _ino, _fh);
+        self.release_handle(_fh);
         reply.ok();
     }
 
@@ -612,14 +1301,31 @@ impl Filesystem for CrustFS {
                 _fh: u64,
                 _datasync: bool,
                 reply: ReplyEmpty) {
-        reply.error(ENOSYS);
-        panic!("fsyncdir not implemented");
+        debug!("fsyncdir: ino: {}, datasync: {}", _ino, _datasync);
+        let _ino = self.inner(_ino);
+        //directory entries live in the directory's own row, so they count as its data
+        match self.sync_inode(_ino, false) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Get file system statistics
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
         debug!("statfs");
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        let capacity = self.fs_metadata_u64("capacity").map(|c| c.unwrap_or(DEFAULT_CAPACITY));
+        let max_inodes = self.fs_metadata_u64("max_inodes").map(|c| c.unwrap_or(DEFAULT_MAX_INODES));
+        match (capacity, max_inodes, self.usage()) {
+            (Ok(capacity), Ok(max_inodes), Ok((bytes, inodes))) => {
+                let (blocks, used, bfree, ffree) = statfs_counts(capacity, max_inodes, bytes, inodes);
+                debug!("statfs: {} of {} blocks, {} of {} inodes used", used, blocks, inodes, max_inodes);
+                reply.statfs(blocks, bfree, bfree, max_inodes, ffree, BLOCK_SIZE as u32, 255, BLOCK_SIZE as u32);
+            },
+            (capacity, max_inodes, usage) => {
+                error!("statfs: {:?} {:?} {:?}", capacity.err(), max_inodes.err(), usage.err());
+                reply.error(EIO);
+            }
+        }
     }
 
     /// Set an extended attribute
@@ -631,30 +1337,68 @@ impl Filesystem for CrustFS {
                 _flags: u32,
                 _position: u32,
                 reply: ReplyEmpty) {
-        reply.error(ENOSYS);
-        panic!("setxattr not implemented");
+        debug!("setxattr: ino: {}, name: {:?}, flags: {}", _ino, _name, _flags);
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        let _ino = self.inner(_ino);
+        //position is only used for resource forks on OS X
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => return reply.error(EINVAL),
+        };
+        match self.set_xattr(_req.uid(), _req.gid(), _ino, name, _value, _flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Get an extended attribute
-    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyData) {
-        // FIXME: If arg.size is zero, the size of the value should be sent with fuse_getxattr_out
-        // FIXME: If arg.size is non-zero, send the value if it fits, or ERANGE otherwise
-        reply.error(ENOSYS);
-        //   panic!("getxattr not implemented");
+    /// If size is zero, the size of the value is sent back so the caller can size
+    /// its buffer. Otherwise the value is sent if it fits, or ERANGE if it doesn't.
+    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        debug!("getxattr: ino: {}, name: {:?}, size: {}", _ino, _name, _size);
+        let _ino = self.inner(_ino);
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => return reply.error(EINVAL),
+        };
+        match self.get_xattr(_req.uid(), _req.gid(), _ino, name) {
+            Ok(ref value) if _size == 0 => reply.size(value.len() as u32),
+            Ok(ref value) if value.len() > _size as usize => reply.error(ERANGE),
+            Ok(value) => reply.data(&value),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// List extended attribute names
-    fn listxattr(&mut self, _req: &Request, _ino: u64, reply: ReplyEmpty) {
-        // FIXME: If arg.size is zero, the size of the attr list should be sent with fuse_getxattr_out
-        // FIXME: If arg.size is non-zero, send the attribute list if it fits, or ERANGE otherwise
-        reply.error(ENOSYS);
-        panic!("listxattr not implemented");
+    /// Size probing works the same way as for getxattr.
+    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
+        debug!("listxattr: ino: {}, size: {}", _ino, _size);
+        let _ino = self.inner(_ino);
+        match self.list_xattrs(_req.uid(), _ino) {
+            Ok(ref list) if _size == 0 => reply.size(list.len() as u32),
+            Ok(ref list) if list.len() > _size as usize => reply.error(ERANGE),
+            Ok(list) => reply.data(&list),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Remove an extended attribute
     fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
-        debug!("removexattr");
-        reply.error(ENOSYS);
+        debug!("removexattr: ino: {}, name: {:?}", _ino, _name);
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        let _ino = self.inner(_ino);
+        let name = match _name.to_str() {
+            Some(name) => name,
+            None => return reply.error(EINVAL),
+        };
+        match self.remove_xattr(_req.uid(), _req.gid(), _ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Check file access permissions
@@ -662,8 +1406,23 @@ impl Filesystem for CrustFS {
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
     fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-    //FIXME implement proper access controls
-        reply.ok();
+        debug!("access: ino: {}, mask: {}", _ino, _mask);
+        if refuses_on_read_only(self.read_only, _mask) {
+            return reply.error(EROFS);
+        }
+        let _ino = self.inner(_ino);
+        match self.get_inode(_ino) {
+            Ok(Some(row)) => match self.check_access(&row, _req.uid(), _req.gid(), _mask) {
+                Ok(true) => reply.ok(),
+                Ok(false) => reply.error(EACCES),
+                Err(err) => reply.error(err),
+            },
+            Ok(None) => reply.error(ENOENT),
+            Err(err) => {
+                error!("access: {:?}", err);
+                reply.error(EIO);
+            }
+        }
     }
 
     /// Create and open a file
@@ -683,69 +1442,19 @@ impl Filesystem for CrustFS {
               _mode: u32,
               _flags: u32,
               reply: ReplyCreate) {
-        println!("create");
-        match _name.to_str() {
-            Some(path) => {
-                println!("Path: {}", path);
-                println!("_mode: {}",_mode);
-                println!("_flags: {}",_flags);
-                println!("_name: {}",_name.is_file());
-                println!("_parent: {}", _parent);
-
-                let (partition,inode) = self.allocate_inode();
-
-                let now = time::get_time();
-                let new_file = FileAttr{
-                    ino:inode,
-                    size:0,blocks:0,
-                    atime:now,mtime:now,ctime:now,crtime:now,
-                    kind:FileType::RegularFile,
-                    perm:0o755,
-                    nlink:0,
-                    uid:0,gid:0,
-                    rdev:0,
-                    flags:0,
-                };
-
-                let statement = CassStatement::new(self.cmds.create_inode, 16);
-                println!("inserting inode:{}",new_file.ino);
-                statement.bind_int64(0, _parent as i64).unwrap();
-                statement.bind_int64(1, new_file.size as i64).unwrap();
-                statement.bind_int64(2, new_file.blocks as i64).unwrap();
-                statement.bind_int64(3, new_file.atime.sec as i64).unwrap();
-                statement.bind_int64(4, new_file.mtime.sec as i64).unwrap();
-                statement.bind_int64(5, new_file.ctime.sec as i64).unwrap();
-                statement.bind_int64(6, new_file.crtime.sec as i64).unwrap();
-                statement.bind_string(7, /* FIXME new_file.kind */ "file").unwrap();
-                statement.bind_int32(8, new_file.perm as i32).unwrap();
-                statement.bind_int32(9, new_file.nlink as i32).unwrap();
-                statement.bind_int32(10, new_file.uid as i32).unwrap();
-                statement.bind_int32(11, new_file.gid as i32).unwrap();
-                statement.bind_int32(12, new_file.rdev as i32).unwrap();
-                statement.bind_int32(13, new_file.flags as i32).unwrap();
-                statement.bind_int64(14, partition as i64).unwrap();
-                statement.bind_int64(15, new_file.ino as i64).unwrap();
-
-                assert!(!self.session.execute_statement(&statement).wait().is_err());
-
-                let statement = CassStatement::new(self.cmds.add_inode_to_parent, 4);
-                println!("adding inode to parent:{}",new_file.ino);
-                let parent_partition = _parent % INODE_PARTITIONS;
-                statement.bind_string(0, path).unwrap();
-                statement.bind_int64(1, inode as i64).unwrap();
-                statement.bind_int64(2, _parent as i64).unwrap();
-                statement.bind_int64(3, parent_partition as i64).unwrap();
-                assert!(!self.session.execute_statement(&statement).wait().is_err());
-
-                //self, ttl: &Timespec, attr: &FileAttr, generation: u64, fh: u64, flags: u32
-
-                //FIXME set correct generation,fh,flags
-                reply.created(&now,&new_file,0,0,0);
+        debug!("create: parent: {}, name: {:?}, mode: {:o}", _parent, _name.to_str(), _mode);
+        if self.read_only {
+            return reply.error(EROFS);
+        }
+        let _parent = self.inner(_parent);
+        match self.make_inode(_req, _parent, _name, FileType::RegularFile, _mode) {
+            //whoever creates a file may open it however they asked to, whatever the mode
+            Ok(attr) => {
+                let fh = self.file_handle(attr.ino, _flags);
+                let flags = if self.handles[&fh].direct_io() {FOPEN_DIRECT_IO} else {0};
+                reply.created(&self.cache.entry_ttl, &self.outer_attr(&attr), 0, fh, flags);
             },
-            None    => {
-                println!("No path specified!!");
-                reply.error(EIO)
-            }
+            Err(err) => reply.error(err),
         }
     }
 
@@ -760,8 +1469,15 @@ impl Filesystem for CrustFS {
              _typ: u32,
              _pid: u32,
              reply: ReplyLock) {
-        reply.error(ENOSYS);
-        panic!("getlk not implemented");
+        debug!("getlk: ino: {}, owner: {}, {}-{} type {}", _ino, _lock_owner, _start, _end, _typ);
+        let _ino = self.inner(_ino);
+        let lock = Lock{client:self.client_id, owner:_lock_owner, start:_start, end:_end,
+            typ:_typ, pid:_pid, expires:0};
+        match self.test_lock(_ino, &lock) {
+            Ok(Some(held)) => reply.locked(held.start, held.end, held.typ, held.pid),
+            Ok(None) => reply.locked(_start, _end, F_UNLCK, 0),
+            Err(err) => reply.error(err),
+        }
     }
 
     /// Acquire, modify or release a POSIX file lock
@@ -782,8 +1498,15 @@ impl Filesystem for CrustFS {
              _pid: u32,
              _sleep: bool,
              reply: ReplyEmpty) {
-        reply.error(ENOSYS);
-        panic!("setlk not implemented");
+        debug!("setlk: ino: {}, owner: {}, {}-{} type {}, sleep: {}", _ino, _lock_owner, _start, _end, _typ, _sleep);
+        //read locks are fine, they only keep writers on other mounts out
+        if self.read_only && _typ == F_WRLCK {
+            return reply.error(EROFS);
+        }
+        let _ino = self.inner(_ino);
+        let lock = Lock{client:self.client_id, owner:_lock_owner, start:_start, end:_end,
+            typ:_typ, pid:_pid, expires:time::get_time().sec + LOCK_LEASE};
+        self.set_lock(_ino, lock, _sleep, reply);
     }
 
     /// Map block index within file to block index within device
@@ -799,9 +1522,28 @@ impl Filesystem for CrustFS {
 mod tests {
 
     use cql_ffi::{CassCluster,CassSession};
-    use super::CrustFS;
+    use super::{CrustFS, INODE_PARTITIONS, next_inode, statfs_counts};
     use fuse::Filesystem;
 
+    #[test]
+    fn inodes_stay_in_their_partition() {
+        assert_eq!(next_inode(3, None), 3);
+        assert_eq!(next_inode(0, None), INODE_PARTITIONS);
+        assert_eq!(next_inode(1, Some(1)), 1 + INODE_PARTITIONS);
+        for partition in 0..INODE_PARTITIONS {
+            assert_eq!(next_inode(partition, None) % INODE_PARTITIONS, partition);
+        }
+    }
+
+    #[test]
+    fn statfs_rounds_and_clamps() {
+        assert_eq!(statfs_counts(1024 * 512, 100, 0, 0), (1024, 0, 1024, 100));
+        //a partial block is a used block
+        assert_eq!(statfs_counts(1024 * 512, 100, 513, 3), (1024, 2, 1022, 97));
+        //over the limits nothing is free
+        assert_eq!(statfs_counts(1024 * 512, 100, 2048 * 512, 101), (1024, 2048, 0, 0));
+    }
+
     #[test]
     /// create a test file inode as a child of the root inode
     fn create_inode() {
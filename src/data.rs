@@ -0,0 +1,733 @@
+//! File contents, stored in CHUNK_SIZE pieces, one row of crustfs.chunks each.
+//! A missing chunk is a hole and reads back as zeros. Which chunks of a file
+//! exist is recorded in crustfs.chunk_map, clustered by chunk so that one
+//! query finds them all; truncate uses it to delete what lies past the new
+//! end, and the blocks of a file count the chunks it lists. No chunk exists
+//! past the stored size of a file, so a chunk that starts at or after it is
+//! known to be new without asking.
+//!
+//! Writes are buffered per open inode and coalesced into whole chunks. A chunk
+//! is sent (without waiting, through the pending write queue) as soon as it is
+//! completely written, so a sequential copy streams whole chunks to the
+//! cluster while the kernel keeps writing. Partial chunks wait in the buffer
+//! until flush, fsync or release, until the file's buffer is older than
+//! WRITE_BUFFER_AGE, or until all buffers together pass WRITE_BUFFER_LIMIT.
+//! The age is checked whenever the mount handles a request: rust-fuse gives
+//! no way to act on the filesystem from outside the request loop.
+//!
+//! The new size, the usage counters, the quotas and the subtree statistics
+//! are brought up to date when a buffer is flushed. Failures of writes sent in the background are
+//! reported by the next flush or fsync, see sync.rs. Usage and quotas count
+//! what a file takes up, its blocks, rather than its size, so a sparse file
+//! only costs the chunks that were written.
+//!
+//! Files no larger than inline_max don't use crustfs.chunks at all: their
+//! contents sit in the inline_data column of their inode row and come back
+//! with the attributes. New files start out inline. Once a write takes a file
+//! past inline_max its buffer is flushed right away, which moves the data to
+//! chunk 0 and clears the column; from then on the file stays chunked. A null
+//! inline_data (any file written before the column existed) means chunked.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::i64;
+
+use libc::{c_int, EIO, ENOENT, EISDIR, EFBIG};
+
+use cql_ffi::{CassStatement, CassError};
+
+use consistency::OpClass;
+use time::{self, Timespec};
+
+use {CrustFS, BLOCK_SIZE};
+
+pub static CHUNK_SIZE:u64 = 128 * 1024;
+//largest file kept inline, unless mkcrustfs was given --inline-max
+pub static DEFAULT_INLINE_MAX:u64 = 4096;
+
+//all write buffers of a mount together, in bytes
+pub static WRITE_BUFFER_LIMIT:usize = 64 * 1024 * 1024;
+//seconds a partially written chunk may wait in a buffer
+pub static WRITE_BUFFER_AGE:i64 = 5;
+
+//chunk indexes are stored as bigint
+static MAX_FILE_SIZE:u64 = 1 << 62;
+
+/// Merge [start, end) into a sorted list of disjoint ranges.
+fn add_range(ranges: &mut Vec<(u64, u64)>, start: u64, end: u64) {
+    let (mut start, mut end) = (start, end);
+    let mut merged = Vec::with_capacity(ranges.len() + 1);
+    for &(s, e) in ranges.iter() {
+        if e < start || s > end {
+            merged.push((s, e));
+        } else {
+            start = cmp::min(start, s);
+            end = cmp::max(end, e);
+        }
+    }
+    merged.push((start, end));
+    merged.sort();
+    *ranges = merged;
+}
+
+fn covers(ranges: &[(u64, u64)], start: u64, end: u64) -> bool {
+    ranges.iter().any(|&(s, e)| s <= start && e >= end)
+}
+
+/// The part of one chunk written since the last flush.
+struct ChunkBuf {
+    data:Vec<u8>,
+    written:Vec<(u64, u64)>,
+}
+
+impl ChunkBuf {
+    /// Copy what was written over the chunk as it was stored.
+    fn overlay(&self, base: &mut Vec<u8>) {
+        if base.len() < self.data.len() {
+            base.resize(self.data.len(), 0);
+        }
+        for &(start, end) in self.written.iter() {
+            base[start as usize..end as usize].clone_from_slice(&self.data[start as usize..end as usize]);
+        }
+    }
+}
+
+pub struct WriteBuffer {
+    chunks:BTreeMap<u64, ChunkBuf>,
+    bytes:usize,
+    //when the oldest data in this buffer was written
+    since:i64,
+    mtime:Timespec,
+    //size as stored, and as it will be once the buffer is flushed
+    base_size:u64,
+    size:u64,
+    part_id:u64,
+    uid:u32,
+    gid:u32,
+    project:u32,
+    //the stored contents while the file is inline
+    inline:Option<Vec<u8>>,
+    //blocks as stored, which is what usage and quotas are charged with, and
+    //what's left of them (truncate may have dropped chunks)
+    base_blocks:u64,
+    blocks:u64,
+    //whole chunks sent already
+    sent:BTreeSet<u64>,
+}
+
+impl WriteBuffer {
+    /// (uid, gid, project) the file's usage is charged to.
+    pub fn owner(&self) -> (u32, u32, u32) {
+        (self.uid, self.gid, self.project)
+    }
+
+    /// Blocks the file takes once the buffer is flushed, with [start, end)
+    /// written as well if write is given. Chunks inside the stored size that
+    /// were holes are only found at flush, so they don't count yet.
+    fn blocks_with(&self, write: Option<(u64, u64)>, inline_max: u64) -> u64 {
+        let mut size = self.size;
+        let mut touched:BTreeSet<u64> = self.sent.iter().chain(self.chunks.keys()).cloned().collect();
+        if let Some((start, end)) = write {
+            size = cmp::max(size, end);
+            if end > start {
+                touched.extend(start / CHUNK_SIZE..(end - 1) / CHUNK_SIZE + 1);
+            }
+        }
+        match self.inline {
+            Some(_) if size <= inline_max => (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            //promoted, the old contents go to chunk 0
+            Some(ref stored) => {
+                if !stored.is_empty() {
+                    touched.insert(0);
+                }
+                touched.len() as u64 * (CHUNK_SIZE / BLOCK_SIZE)
+            },
+            None => self.blocks + touched.iter().filter(|chunk| **chunk * CHUNK_SIZE >= self.base_size).count() as u64
+                * (CHUNK_SIZE / BLOCK_SIZE),
+        }
+    }
+
+    /// Bytes flush_buffer will charge to usage and quotas that aren't charged yet.
+    pub fn uncharged(&self, inline_max: u64) -> i64 {
+        (self.blocks_with(None, inline_max) as i64 - self.base_blocks as i64) * BLOCK_SIZE as i64
+    }
+}
+
+pub fn new_write_buffers() -> HashMap<u64, WriteBuffer> {
+    HashMap::new()
+}
+
+impl CrustFS {
+    fn select_chunk(&self, ino: u64, chunk: u64) -> Result<Option<Vec<u8>>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_chunk, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, chunk as i64).unwrap();
+        let result = try!(self.read_data(&statement).wait());
+        Ok(result.first_row().map(|row| row.get_column(0).unwrap().get_bytes().unwrap()))
+    }
+
+    fn queue_chunk(&mut self, ino: u64, chunk: u64, data: &[u8]) {
+        let statement = CassStatement::new(self.cmds.insert_chunk, 3);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, chunk as i64).unwrap();
+        statement.bind_bytes(2, data).unwrap();
+        self.queue_write(ino, OpClass::DataWrite, statement);
+    }
+
+    fn queue_chunk_map(&mut self, ino: u64, chunk: u64) {
+        let statement = CassStatement::new(self.cmds.insert_chunk_map, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, chunk as i64).unwrap();
+        self.queue_write(ino, OpClass::DataWrite, statement);
+    }
+
+    /// The chunks first..last (inclusive) of ino that exist.
+    pub fn chunk_map(&self, ino: u64, first: u64, last: u64) -> Result<Vec<u64>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_chunk_map, 3);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, first as i64).unwrap();
+        statement.bind_int64(2, cmp::min(last, i64::MAX as u64) as i64).unwrap();
+        let result = try!(self.read_data(&statement).wait());
+        Ok(result.iter().map(|row| row.get_column(0).unwrap().get_int64().unwrap() as u64).collect())
+    }
+
+    /// Send a whole chunk, recording it in the chunk map if it is new.
+    fn send_chunk(&mut self, ino: u64, chunk: u64, data: &[u8]) {
+        self.queue_chunk(ino, chunk, data);
+        //whether a chunk inside the old size was there before is left to flush to find out
+        let new = {
+            let buffer = self.write_buffers.get_mut(&ino).unwrap();
+            buffer.sent.insert(chunk) && chunk * CHUNK_SIZE >= buffer.base_size
+        };
+        if new {
+            self.queue_chunk_map(ino, chunk);
+        }
+    }
+
+    /// Add the chunks of an overwrite that weren't there before to the chunk
+    /// map, returning how many that was.
+    fn map_overwritten(&mut self, ino: u64, chunks: &BTreeSet<u64>) -> u64 {
+        let (first, last) = match (chunks.iter().next(), chunks.iter().next_back()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return 0,
+        };
+        let known:HashSet<u64> = match self.chunk_map(ino, first, last) {
+            Ok(known) => known.into_iter().collect(),
+            Err(err) => {
+                error!("flush_buffer: unable to read chunk map of {}: {:?}", ino, err);
+                self.defer_error(ino, EIO);
+                return 0;
+            }
+        };
+        let mut added = 0;
+        for chunk in chunks.iter().filter(|chunk| !known.contains(*chunk)) {
+            self.queue_chunk_map(ino, *chunk);
+            added += 1;
+        }
+        added
+    }
+
+    fn queue_size(&mut self, ino: u64, buffer: &WriteBuffer, inline: Option<&[u8]>, blocks: u64) {
+        let (statement, first) = match inline {
+            Some(data) => {
+                let statement = CassStatement::new(self.cmds.update_inline, 9);
+                statement.bind_bytes(0, data).unwrap();
+                (statement, 1)
+            },
+            None if buffer.inline.is_some() => (CassStatement::new(self.cmds.update_size_chunked, 8), 0),
+            None => (CassStatement::new(self.cmds.update_size, 8), 0),
+        };
+        statement.bind_int64(first, buffer.size as i64).unwrap();
+        statement.bind_int64(first + 1, blocks as i64).unwrap();
+        statement.bind_int64(first + 2, buffer.mtime.sec).unwrap();
+        statement.bind_int32(first + 3, buffer.mtime.nsec).unwrap();
+        statement.bind_int64(first + 4, buffer.mtime.sec).unwrap();
+        statement.bind_int32(first + 5, buffer.mtime.nsec).unwrap();
+        statement.bind_int64(first + 6, buffer.part_id as i64).unwrap();
+        statement.bind_int64(first + 7, ino as i64).unwrap();
+        //the size goes out with the data, fdatasync must wait for it as well
+        self.queue_write(ino, OpClass::DataWrite, statement);
+    }
+
+    fn new_write_buffer(&mut self, ino: u64) -> Result<WriteBuffer, c_int> {
+        //the row has to reflect our last flush
+        self.wait_writes(ino);
+        let row = match self.get_inode(ino) {
+            Ok(Some(ref row)) if row.is_placeholder() => return Err(ENOENT),
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(ENOENT),
+            Err(err) => {
+                error!("write: unable to read inode {}: {:?}", ino, err);
+                return Err(EIO);
+            }
+        };
+        if row.is_dir() {
+            return Err(EISDIR);
+        }
+        let now = time::get_time();
+        Ok(WriteBuffer{chunks:BTreeMap::new(), bytes:0, since:now.sec, mtime:now,
+            base_size:row.size, size:row.size, part_id:row.part_id,
+            uid:row.uid, gid:row.gid, project:row.project, inline:row.inline_data,
+            base_blocks:row.blocks, blocks:row.blocks, sent:BTreeSet::new()})
+    }
+
+    /// Take data written at offset (or, with append, at EOF) into the buffer of ino.
+    pub fn buffer_write(&mut self, ino: u64, offset: u64, data: &[u8], append: bool) -> Result<u32, c_int> {
+        self.flush_expired_buffers();
+        self.drop_pages(ino);
+        if !self.write_buffers.contains_key(&ino) {
+            let buffer = try!(self.new_write_buffer(ino));
+            self.write_buffers.insert(ino, buffer);
+        }
+        let offset = if append {self.write_buffers[&ino].size} else {offset};
+        let end = offset + data.len() as u64;
+        if end > MAX_FILE_SIZE {
+            return Err(EFBIG);
+        }
+        let (uid, gid, project, grown) = {
+            let buffer = &self.write_buffers[&ino];
+            let grown = buffer.blocks_with(Some((offset, end)), self.inline_max) as i64
+                - buffer.blocks_with(None, self.inline_max) as i64;
+            (buffer.uid, buffer.gid, buffer.project, grown * BLOCK_SIZE as i64)
+        };
+        if grown > 0 {
+            try!(self.check_quota(uid, gid, project, grown, 0));
+        }
+
+        let mut full = Vec::new();
+        let mut added = 0;
+        let promote = {
+            let buffer = self.write_buffers.get_mut(&ino).unwrap();
+            if buffer.chunks.is_empty() {
+                buffer.since = time::get_time().sec;
+            }
+            let mut pos = offset;
+            while pos < end {
+                let chunk = pos / CHUNK_SIZE;
+                let start = pos % CHUNK_SIZE;
+                let stop = cmp::min(CHUNK_SIZE, start + end - pos);
+                let piece = &data[(pos - offset) as usize..(pos - offset + stop - start) as usize];
+                let buf = buffer.chunks.entry(chunk).or_insert_with(|| ChunkBuf{data:Vec::new(), written:Vec::new()});
+                if buf.data.len() < stop as usize {
+                    added += stop as usize - buf.data.len();
+                    buf.data.resize(stop as usize, 0);
+                }
+                buf.data[start as usize..stop as usize].clone_from_slice(piece);
+                add_range(&mut buf.written, start, stop);
+                //an inline file is written out whole when it is flushed or promoted
+                if covers(&buf.written, 0, CHUNK_SIZE) && buffer.inline.is_none() {
+                    full.push(chunk);
+                }
+                pos += stop - start;
+            }
+            buffer.size = cmp::max(buffer.size, end);
+            buffer.mtime = time::get_time();
+            buffer.bytes += added;
+            buffer.inline.is_some() && buffer.size > self.inline_max
+        };
+        self.buffered_bytes += added;
+        if promote {
+            self.flush_buffer(ino);
+        }
+
+        //whole chunks are final, no need to hold on to them
+        for chunk in full.into_iter() {
+            let buf = self.write_buffers.get_mut(&ino).unwrap().chunks.remove(&chunk).unwrap();
+            self.write_buffers.get_mut(&ino).unwrap().bytes -= buf.data.len();
+            self.buffered_bytes -= buf.data.len();
+            self.send_chunk(ino, chunk, &buf.data);
+        }
+
+        while self.buffered_bytes > WRITE_BUFFER_LIMIT {
+            let largest = self.write_buffers.iter().max_by_key(|&(_, buffer)| buffer.bytes).map(|(ino, _)| *ino);
+            match largest {
+                Some(largest) => self.flush_buffer(largest),
+                None => break,
+            }
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// Send everything buffered for ino, followed by its new size. Errors are
+    /// deferred to the next flush or fsync like those of the writes themselves.
+    pub fn flush_buffer(&mut self, ino: u64) {
+        let buffer = match self.write_buffers.remove(&ino) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        self.buffered_bytes -= buffer.bytes;
+
+        let blocks = match buffer.inline {
+            Some(ref stored) => self.flush_inline(ino, &buffer, stored),
+            None => {
+                let blocks = self.flush_chunks(ino, &buffer);
+                self.queue_size(ino, &buffer, None, blocks);
+                blocks
+            }
+        };
+
+        let allocated = (blocks as i64 - buffer.base_blocks as i64) * BLOCK_SIZE as i64;
+        if allocated != 0 {
+            if let Err(err) = self.update_usage(allocated, 0) {
+                error!("flush_buffer: unable to update usage: {:?}", err);
+            }
+            if let Err(err) = self.update_quota_usage(buffer.uid, buffer.gid, buffer.project, allocated, 0) {
+                error!("flush_buffer: unable to update quota usage: {:?}", err);
+            }
+        }
+        //subtree statistics count the apparent size, like du --apparent-size
+        let grown = buffer.size as i64 - buffer.base_size as i64;
+        self.note_rstats(ino, grown, 0, 0, buffer.mtime);
+        self.cache.invalidate_attr(ino);
+        self.log_change(ino, None);
+    }
+
+    /// Write the contents of an inline file back into its row, or move them
+    /// to chunks if the file has outgrown inline_max, and return its blocks.
+    fn flush_inline(&mut self, ino: u64, buffer: &WriteBuffer, stored: &[u8]) -> u64 {
+        let mut first = stored.to_vec();
+        if let Some(buf) = buffer.chunks.get(&0) {
+            buf.overlay(&mut first);
+        }
+        if buffer.size <= self.inline_max {
+            first.resize(buffer.size as usize, 0);
+            let blocks = (buffer.size + BLOCK_SIZE - 1) / BLOCK_SIZE;
+            self.queue_size(ino, buffer, Some(&first), blocks);
+            return blocks;
+        }
+        //nothing was stored in chunks yet, so every chunk is new and just what we hold
+        let mut chunks:Vec<(u64, &[u8])> = buffer.chunks.iter()
+            .filter(|&(chunk, _)| *chunk != 0).map(|(chunk, buf)| (*chunk, &buf.data[..])).collect();
+        if !first.is_empty() {
+            chunks.push((0, &first[..]));
+        }
+        for &(chunk, data) in chunks.iter() {
+            self.queue_chunk(ino, chunk, data);
+            self.queue_chunk_map(ino, chunk);
+        }
+        //the row may only say "chunked" once the chunks are there to read
+        self.wait_writes(ino);
+        let blocks = chunks.len() as u64 * (CHUNK_SIZE / BLOCK_SIZE);
+        self.queue_size(ino, buffer, None, blocks);
+        blocks
+    }
+
+    /// Send the chunks of a chunked file, merging partial ones with what is
+    /// stored, and return its blocks once they are written.
+    fn flush_chunks(&mut self, ino: u64, buffer: &WriteBuffer) -> u64 {
+        //a chunk at or past the old EOF had nothing in it we'd have to keep,
+        //unless this buffer already sent it whole
+        let blind = |chunk: u64, buf: &ChunkBuf| covers(&buf.written, 0, buf.data.len() as u64)
+            && (buf.data.len() as u64 == CHUNK_SIZE
+                || (chunk * CHUNK_SIZE + buf.data.len() as u64 >= buffer.base_size && !buffer.sent.contains(&chunk)));
+        let touched:BTreeSet<u64> = buffer.sent.iter().chain(buffer.chunks.keys()).cloned().collect();
+        let (overwritten, new):(BTreeSet<u64>, BTreeSet<u64>) = touched.into_iter()
+            .partition(|chunk| chunk * CHUNK_SIZE < buffer.base_size);
+        let mut blocks = buffer.blocks + new.len() as u64 * (CHUNK_SIZE / BLOCK_SIZE);
+        for chunk in new.iter().filter(|chunk| !buffer.sent.contains(*chunk)) {
+            self.queue_chunk_map(ino, *chunk);
+        }
+        //anything we read back has to include what we sent before, the chunk map
+        //included, and a chunk sent before must land before we send it again
+        let resent = buffer.chunks.keys().any(|chunk| buffer.sent.contains(chunk));
+        if !overwritten.is_empty() || resent {
+            self.wait_writes(ino);
+        }
+        blocks += self.map_overwritten(ino, &overwritten) * (CHUNK_SIZE / BLOCK_SIZE);
+        for (chunk, buf) in buffer.chunks.iter() {
+            if blind(*chunk, buf) {
+                self.queue_chunk(ino, *chunk, &buf.data);
+                continue;
+            }
+            match self.select_chunk(ino, *chunk) {
+                Ok(stored) => {
+                    let mut data = stored.unwrap_or(Vec::new());
+                    buf.overlay(&mut data);
+                    self.queue_chunk(ino, *chunk, &data);
+                },
+                Err(err) => {
+                    error!("flush_buffer: unable to read chunk {} of {}: {:?}", chunk, ino, err);
+                    self.defer_error(ino, EIO);
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Delete the chunks of ino past size and cut the one size falls in short,
+    /// returning how many chunks are gone.
+    fn cut_chunks(&mut self, ino: u64, size: u64) -> Result<u64, c_int> {
+        if size % CHUNK_SIZE != 0 {
+            let chunk = size / CHUNK_SIZE;
+            match self.select_chunk(ino, chunk) {
+                Ok(Some(mut data)) => if data.len() as u64 > size % CHUNK_SIZE {
+                    data.truncate((size % CHUNK_SIZE) as usize);
+                    self.queue_chunk(ino, chunk, &data);
+                },
+                Ok(None) => {},
+                Err(err) => {
+                    error!("truncate: unable to read chunk {} of {}: {:?}", chunk, ino, err);
+                    return Err(EIO);
+                }
+            }
+        }
+        let gone = match self.chunk_map(ino, (size + CHUNK_SIZE - 1) / CHUNK_SIZE, MAX_FILE_SIZE / CHUNK_SIZE) {
+            Ok(gone) => gone,
+            Err(err) => {
+                error!("truncate: unable to read chunk map of {}: {:?}", ino, err);
+                return Err(EIO);
+            }
+        };
+        for chunk in gone.iter() {
+            for cmd in [self.cmds.delete_chunk, self.cmds.delete_chunk_map].iter() {
+                let statement = CassStatement::new(*cmd, 2);
+                statement.bind_int64(0, ino as i64).unwrap();
+                statement.bind_int64(1, *chunk as i64).unwrap();
+                self.queue_write(ino, OpClass::DataWrite, statement);
+            }
+        }
+        //a later write to one of these chunks must not race its delete
+        self.wait_writes(ino);
+        Ok(gone.len() as u64)
+    }
+
+    /// Set the size of ino, for setattr. Growing leaves a hole; shrinking drops
+    /// the chunks past the new end. Anything buffered is flushed first.
+    pub fn truncate(&mut self, ino: u64, size: u64) -> Result<(), c_int> {
+        if size > MAX_FILE_SIZE {
+            return Err(EFBIG);
+        }
+        self.flush_buffer(ino);
+        let mut buffer = try!(self.new_write_buffer(ino));
+        //growing only leaves a hole, unless an inline file has to be promoted
+        if size > buffer.size {
+            let grown = buffer.blocks_with(Some((size, size)), self.inline_max) as i64 - buffer.blocks as i64;
+            if grown > 0 {
+                try!(self.check_quota(buffer.uid, buffer.gid, buffer.project, grown * BLOCK_SIZE as i64, 0));
+            }
+        }
+        match buffer.inline {
+            Some(ref mut data) => data.truncate(size as usize),
+            None => if size < buffer.size {
+                let gone = try!(self.cut_chunks(ino, size));
+                buffer.blocks = buffer.blocks.saturating_sub(gone * (CHUNK_SIZE / BLOCK_SIZE));
+            },
+        }
+        buffer.size = size;
+        buffer.mtime = time::get_time();
+        self.drop_pages(ino);
+        self.write_buffers.insert(ino, buffer);
+        self.flush_buffer(ino);
+        Ok(())
+    }
+
+    /// The timer half of write-back: flush buffers that have waited long enough.
+    /// Access times and subtree statistics waiting to be written ride along.
+    pub fn flush_expired_buffers(&mut self) {
+        self.flush_expired_atimes();
+        self.flush_expired_rstats();
+        let cutoff = time::get_time().sec - WRITE_BUFFER_AGE;
+        let expired:Vec<u64> = self.write_buffers.iter()
+            .filter(|&(_, buffer)| buffer.since <= cutoff).map(|(ino, _)| *ino).collect();
+        for ino in expired.into_iter() {
+            self.flush_buffer(ino);
+        }
+    }
+
+    pub fn flush_all_buffers(&mut self) {
+        let inodes:Vec<u64> = self.write_buffers.keys().cloned().collect();
+        for ino in inodes.into_iter() {
+            self.flush_buffer(ino);
+        }
+    }
+
+    /// The size of ino including writes still sitting in its buffer.
+    pub fn buffered_size(&self, ino: u64) -> Option<u64> {
+        self.write_buffers.get(&ino).map(|buffer| buffer.size)
+    }
+
+    /// The blocks of ino including writes still sitting in its buffer.
+    pub fn buffered_blocks(&self, ino: u64) -> Option<u64> {
+        self.write_buffers.get(&ino).map(|buffer| buffer.blocks_with(None, self.inline_max))
+    }
+
+    /// The stored contents of ino if it is an inline file.
+    fn inline_data(&mut self, ino: u64) -> Result<Option<Vec<u8>>, c_int> {
+        if let Some(data) = self.cache.inline(ino) {
+            return Ok(data);
+        }
+        match self.get_inode(ino) {
+            Ok(Some(row)) => {
+                self.cache.insert_inline(ino, row.inline_data.clone());
+                Ok(row.inline_data)
+            },
+            Ok(None) => Err(ENOENT),
+            Err(err) => {
+                error!("read: unable to read inode {}: {:?}", ino, err);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Read up to size bytes at offset, seeing our own buffered writes.
+    pub fn read_range(&mut self, fh: u64, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        self.flush_expired_buffers();
+        let attr = try!(self.cached_attr(ino));
+        let file_size = self.buffered_size(ino).unwrap_or(attr.size);
+        if offset >= file_size || size == 0 {
+            return Ok(Vec::new());
+        }
+        //chunks sent in the background must have landed before we read them back
+        self.wait_writes(ino);
+        let end = cmp::min(file_size, offset + size as u64);
+        if let Some(mut data) = try!(self.inline_data(ino)) {
+            if let Some(buf) = self.write_buffers.get(&ino).and_then(|buffer| buffer.chunks.get(&0)) {
+                buf.overlay(&mut data);
+            }
+            data.resize(end as usize, 0);
+            return Ok(data[offset as usize..].to_vec());
+        }
+        let mut chunks = try!(self.fetch_chunks(fh, ino, offset, offset / CHUNK_SIZE, (end - 1) / CHUNK_SIZE, end, file_size));
+        let mut out = Vec::with_capacity((end - offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let chunk = pos / CHUNK_SIZE;
+            let mut data = chunks.remove(&chunk).unwrap();
+            if let Some(buf) = self.write_buffers.get(&ino).and_then(|buffer| buffer.chunks.get(&chunk)) {
+                buf.overlay(&mut data);
+            }
+            let start = pos % CHUNK_SIZE;
+            let stop = cmp::min(CHUNK_SIZE, start + end - pos);
+            //holes and the tail of a short chunk read as zeros
+            data.resize(CHUNK_SIZE as usize, 0);
+            out.extend(data[start as usize..stop as usize].iter().cloned());
+            pos += stop - start;
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use cql_ffi::{CassConsistency, CassSession, CassStatement};
+    use rand::{self, Rng};
+    use time;
+
+    use cluster::ClusterConfig;
+    use {CrustFS, INODE_PARTITIONS, ROOT_INODE};
+    use super::{WriteBuffer, CHUNK_SIZE, add_range, covers};
+
+    /// A mount's worth of CrustFS on the local cluster and an empty chunked
+    /// file to write to, or None when there is no cluster to talk to.
+    fn scratch_file() -> Option<(CrustFS, u64)> {
+        let session = CassSession::new();
+        match session.connect(&ClusterConfig::new().cluster()).wait() {
+            Err(fail) => {
+                println!("fail: {}", fail);
+                None
+            },
+            Ok(session) => {
+                let crustfs = CrustFS::build(session);
+                crustfs.upgrade_schema().unwrap();
+                //a row with a parent but no kind or inline_data reads as an empty chunked file
+                let ino:u64 = rand::thread_rng().gen_range(1 << 40, 1 << 50);
+                let statement = CassStatement::new(crustfs.cmds.update_parent_inode, 3);
+                statement.bind_int64(0, ROOT_INODE as i64).unwrap();
+                statement.bind_int64(1, (ino % INODE_PARTITIONS) as i64).unwrap();
+                statement.bind_int64(2, ino as i64).unwrap();
+                crustfs.write_meta(&statement).wait().unwrap();
+                Some((crustfs, ino))
+            }
+        }
+    }
+
+    fn discard(mut crustfs: CrustFS, ino: u64) {
+        crustfs.truncate(ino, 0).unwrap();
+        let statement = CassStatement::new(crustfs.cmds.delete_inode, 2);
+        statement.bind_int64(0, (ino % INODE_PARTITIONS) as i64).unwrap();
+        statement.bind_int64(1, ino as i64).unwrap();
+        crustfs.write_meta(&statement).wait().unwrap();
+    }
+
+    fn buffer(size: u64, blocks: u64, inline: Option<Vec<u8>>) -> WriteBuffer {
+        WriteBuffer{chunks:BTreeMap::new(), bytes:0, since:0, mtime:time::get_time(),
+            base_size:size, size:size, part_id:0, uid:0, gid:0, project:0, inline:inline,
+            base_blocks:blocks, blocks:blocks, sent:BTreeSet::new()}
+    }
+
+    #[test]
+    fn ranges_merge() {
+        let mut ranges = Vec::new();
+        add_range(&mut ranges, 10, 20);
+        add_range(&mut ranges, 30, 40);
+        assert!(!covers(&ranges, 10, 40));
+        add_range(&mut ranges, 20, 30);
+        assert_eq!(ranges, vec![(10, 40)]);
+        add_range(&mut ranges, 0, 10);
+        assert!(covers(&ranges, 0, 40));
+    }
+
+    #[test]
+    fn sparse_writes_charge_chunks() {
+        let chunk_blocks = CHUNK_SIZE / 512;
+        //one byte far out costs one chunk, not the hole before it
+        let file = buffer(0, 0, None);
+        assert_eq!(file.blocks_with(Some((1 << 40, (1 << 40) + 1)), 4096), chunk_blocks);
+        //writes across a chunk boundary cost both
+        assert_eq!(file.blocks_with(Some((CHUNK_SIZE - 1, CHUNK_SIZE + 1)), 4096), 2 * chunk_blocks);
+        //a chunk inside the stored size is taken to be there already
+        let file = buffer(3 * CHUNK_SIZE, 3 * chunk_blocks, None);
+        assert_eq!(file.blocks_with(Some((CHUNK_SIZE, CHUNK_SIZE + 10)), 4096), 3 * chunk_blocks);
+        assert_eq!(file.uncharged(4096), 0);
+    }
+
+    #[test]
+    fn inline_files_charge_blocks() {
+        let file = buffer(100, 1, Some(vec![1; 100]));
+        assert_eq!(file.blocks_with(Some((100, 1000)), 4096), 2);
+        //past inline_max the old contents and the new write each take a chunk
+        assert_eq!(file.blocks_with(Some((2 * CHUNK_SIZE, 2 * CHUNK_SIZE + 1)), 4096), 2 * CHUNK_SIZE / 512);
+        //growing by truncate alone only promotes chunk 0
+        assert_eq!(file.blocks_with(Some((1 << 40, 1 << 40)), 4096), CHUNK_SIZE / 512);
+    }
+
+    #[test]
+    fn rewrite_after_truncate_survives_fsync() {
+        let (mut crustfs, ino) = match scratch_file() {
+            Some(file) => file,
+            None => return,
+        };
+        //every queued write goes out at the fsync level and fsync only waits
+        crustfs.consistency.fsync = Some(CassConsistency::ONE);
+        crustfs.buffer_write(ino, 0, &vec![1; CHUNK_SIZE as usize], false).unwrap();
+        crustfs.truncate(ino, 0).unwrap();
+        crustfs.buffer_write(ino, 0, &vec![2; CHUNK_SIZE as usize], false).unwrap();
+        crustfs.flush_buffer(ino);
+        crustfs.sync_inode(ino, false).unwrap();
+        //the first write and the delete must not come back over the rewrite
+        assert_eq!(crustfs.select_chunk(ino, 0).unwrap(), Some(vec![2; CHUNK_SIZE as usize]));
+        assert_eq!(crustfs.chunk_map(ino, 0, 0).unwrap(), vec![0]);
+        discard(crustfs, ino);
+    }
+
+    #[test]
+    fn partial_rewrite_of_a_sent_chunk() {
+        let (mut crustfs, ino) = match scratch_file() {
+            Some(file) => file,
+            None => return,
+        };
+        //the whole chunk is sent right away, the rewrite of its start waits for flush
+        crustfs.buffer_write(ino, 0, &vec![1; CHUNK_SIZE as usize], false).unwrap();
+        crustfs.buffer_write(ino, 0, &[2; 10], false).unwrap();
+        crustfs.flush_buffer(ino);
+        crustfs.sync_inode(ino, false).unwrap();
+        let mut expected = vec![1; CHUNK_SIZE as usize];
+        for byte in expected[..10].iter_mut() {
+            *byte = 2;
+        }
+        assert_eq!(crustfs.select_chunk(ino, 0).unwrap(), Some(expected));
+        discard(crustfs, ino);
+    }
+}
@@ -0,0 +1,161 @@
+//! Open file and directory handles.
+//!
+//! open, create and opendir each get a fresh fh, indexing a Handle that
+//! lives until the matching release or releasedir. A file handle remembers
+//! how the file was opened and carries its own read-ahead state, so two
+//! readers of one file don't break each other's sequential detection. A
+//! directory handle holds the listing taken at opendir, which readdir pages
+//! through, so entries added or removed meanwhile can't make it skip or
+//! repeat names.
+//!
+//! Write buffers stay per inode (see data.rs): reads through any handle, and
+//! the size reported by getattr, have to see every write made through this
+//! mount.
+
+use std::collections::HashMap;
+
+use libc::{c_int, EACCES, EIO, ENOENT, ENOTDIR, EROFS};
+
+use access::{R_OK, W_OK};
+use readahead::ReadState;
+use CrustFS;
+
+//open flags, Linux values
+static O_ACCMODE:u32 = 0o3;
+static O_WRONLY:u32 = 0o1;
+static O_RDWR:u32 = 0o2;
+static O_APPEND:u32 = 0o2000;
+static O_DIRECT:u32 = 0o40000;
+//open reply flags
+pub static FOPEN_DIRECT_IO:u32 = 1 << 0;
+
+pub struct Handle {
+    pub ino:u64,
+    pub flags:u32,
+    pub read:Option<ReadState>,
+    //(inode, name) as of opendir, "." and ".." first
+    pub entries:Option<Vec<(u64, String)>>,
+}
+
+impl Handle {
+    pub fn append(&self) -> bool {
+        self.flags & O_APPEND != 0
+    }
+
+    pub fn direct_io(&self) -> bool {
+        self.flags & O_DIRECT != 0
+    }
+
+    /// Opened for writing, which lets ftruncate through it whatever the mode.
+    pub fn writable(&self) -> bool {
+        access_mask(self.flags) & W_OK != 0
+    }
+}
+
+pub fn new_handles() -> HashMap<u64, Handle> {
+    HashMap::new()
+}
+
+/// The access an open with flags asks for, as R_OK/W_OK.
+fn access_mask(flags: u32) -> u32 {
+    match flags & O_ACCMODE {
+        mode if mode == O_WRONLY => W_OK,
+        mode if mode == O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    }
+}
+
+/// Does a read-only mount refuse access asking for mask? Only writing is
+/// refused; reading and searching still go to the permission check.
+pub fn refuses_on_read_only(read_only: bool, mask: u32) -> bool {
+    read_only && mask & W_OK != 0
+}
+
+impl CrustFS {
+    fn new_handle(&mut self, handle: Handle) -> u64 {
+        //0 is what the kernel sees when a filesystem keeps no handles
+        self.next_fh += 1;
+        self.handles.insert(self.next_fh, handle);
+        self.next_fh
+    }
+
+    /// Check that uid/gid may open ino with flags and hand out a file handle.
+    pub fn open_file(&mut self, uid: u32, gid: u32, ino: u64, flags: u32) -> Result<u64, c_int> {
+        if refuses_on_read_only(self.read_only, access_mask(flags)) {
+            return Err(EROFS);
+        }
+        let row = match self.get_inode(ino) {
+            Ok(Some(ref row)) if row.is_placeholder() => return Err(ENOENT),
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(ENOENT),
+            Err(err) => {
+                error!("open: unable to read inode {}: {:?}", ino, err);
+                return Err(EIO);
+            }
+        };
+        if !try!(self.check_access(&row, uid, gid, access_mask(flags))) {
+            return Err(EACCES);
+        }
+        Ok(self.file_handle(ino, flags))
+    }
+
+    /// A file handle for ino without any checks, for create.
+    pub fn file_handle(&mut self, ino: u64, flags: u32) -> u64 {
+        self.new_handle(Handle{ino:ino, flags:flags, read:None, entries:None})
+    }
+
+    /// Check that uid/gid may list ino and take a snapshot of its entries.
+    pub fn open_dir(&mut self, uid: u32, gid: u32, ino: u64, flags: u32) -> Result<u64, c_int> {
+        let row = match self.get_inode(ino) {
+            Ok(Some(ref row)) if row.is_placeholder() => return Err(ENOENT),
+            Ok(Some(row)) => row,
+            Ok(None) => return Err(ENOENT),
+            Err(err) => {
+                error!("opendir: unable to read inode {}: {:?}", ino, err);
+                return Err(EIO);
+            }
+        };
+        if !row.is_dir() {
+            return Err(ENOTDIR);
+        }
+        if !try!(self.check_access(&row, uid, gid, R_OK)) {
+            return Err(EACCES);
+        }
+        //".." of the mount's root is the root again
+        let parent = if ino == self.root {ino} else {row.parent_inode.unwrap_or(ino)};
+        let mut entries = vec![(ino, ".".to_string()), (parent, "..".to_string())];
+        entries.extend(row.dir_contents.iter().map(|(name, child)| (*child, name.clone())));
+        Ok(self.new_handle(Handle{ino:ino, flags:flags, read:None, entries:Some(entries)}))
+    }
+
+    pub fn release_handle(&mut self, fh: u64) {
+        if self.handles.remove(&fh).is_none() {
+            debug!("release: unknown fh {}", fh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use access::{R_OK, W_OK, X_OK};
+    use super::{access_mask, refuses_on_read_only, O_APPEND, O_RDWR, O_WRONLY};
+
+    #[test]
+    fn open_flags_to_mask() {
+        assert_eq!(access_mask(0), R_OK);
+        assert_eq!(access_mask(O_WRONLY), W_OK);
+        assert_eq!(access_mask(O_RDWR), R_OK | W_OK);
+        assert_eq!(access_mask(O_WRONLY | O_APPEND), W_OK);
+    }
+
+    #[test]
+    fn read_only_refuses_writes() {
+        assert!(!refuses_on_read_only(true, access_mask(0)));
+        assert!(refuses_on_read_only(true, access_mask(O_WRONLY)));
+        assert!(refuses_on_read_only(true, access_mask(O_RDWR)));
+        assert!(!refuses_on_read_only(true, R_OK | X_OK));
+        assert!(refuses_on_read_only(true, W_OK | X_OK));
+        assert!(!refuses_on_read_only(false, access_mask(O_RDWR)));
+        assert!(!refuses_on_read_only(false, W_OK));
+    }
+}
@@ -0,0 +1,141 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use cql_ffi::CassRow;
+
+use fuse::{FileAttr, FileType};
+
+use time::Timespec;
+
+use BLOCK_SIZE;
+
+/// A full row of crustfs.inode, as returned by select_inode and
+/// select_partition_inodes. Columns that are NULL (as they are in a
+/// placeholder row left behind by allocate_inode) come back as None.
+#[derive(Debug)]
+pub struct InodeRow {
+    pub part_id:u64,
+    pub inode:u64,
+    pub dir_contents:BTreeMap<String,u64>,
+    pub parent_inode:Option<u64>,
+    pub size:u64,
+    pub blocks:u64,
+    pub atime:Timespec,
+    pub mtime:Timespec,
+    pub ctime:Timespec,
+    pub crtime:Timespec,
+    pub kind:Option<String>,
+    pub perm:u32,
+    pub nlink:u32,
+    pub uid:u32,
+    pub gid:u32,
+    pub rdev:u32,
+    pub flags:u32,
+    pub project:u32,
+    //the whole contents of a small file, None once it is stored in chunks
+    pub inline_data:Option<Vec<u8>>,
+}
+
+//what getdents spends on an entry besides its name: inode, offset, length,
+//type and the name's terminating NUL
+static DIRENT_OVERHEAD:u64 = 20;
+
+/// The size of a directory holding contents, plus "." and "..", in bytes.
+pub fn dir_size(contents: &BTreeMap<String,u64>) -> u64 {
+    //"." and ".." to start with
+    contents.keys().fold(1 + 2 + 2 * DIRENT_OVERHEAD, |size, name| size + name.len() as u64 + DIRENT_OVERHEAD)
+}
+
+fn get_int64(row: &CassRow, idx: u64) -> Option<i64> {
+    let column = row.get_column(idx).unwrap();
+    if column.is_null() {None} else {column.get_int64().ok()}
+}
+
+/// Seconds and nanoseconds kept in two columns. Rows written before the
+/// nanosecond columns existed have them null, which reads as whole seconds.
+fn get_time(row: &CassRow, sec: u64, nsec: u64) -> Timespec {
+    Timespec::new(get_int64(row, sec).unwrap_or(0), get_int32(row, nsec).unwrap_or(0))
+}
+
+fn get_int32(row: &CassRow, idx: u64) -> Option<i32> {
+    let column = row.get_column(idx).unwrap();
+    if column.is_null() {None} else {column.get_int32().ok()}
+}
+
+impl InodeRow {
+    /// Column order must match select_inode.
+    pub fn from_row(row: &CassRow) -> InodeRow {
+        let mut dir_contents = BTreeMap::new();
+        let contents = row.get_column(2).unwrap();
+        if !contents.is_null() {
+            for (name, child) in contents.map_iter().unwrap() {
+                dir_contents.insert(name.get_string().unwrap().to_string(), child.get_int64().unwrap() as u64);
+            }
+        }
+        let kind = row.get_column(10).unwrap();
+        let inline_data = row.get_column(18).unwrap();
+        InodeRow{
+            part_id:get_int64(row, 0).unwrap() as u64,
+            inode:get_int64(row, 1).unwrap() as u64,
+            dir_contents:dir_contents,
+            parent_inode:get_int64(row, 3).map(|p| p as u64),
+            size:get_int64(row, 4).unwrap_or(0) as u64,
+            blocks:get_int64(row, 5).unwrap_or(0) as u64,
+            atime:get_time(row, 6, 19),
+            mtime:get_time(row, 7, 20),
+            ctime:get_time(row, 8, 21),
+            crtime:get_time(row, 9, 22),
+            kind:if kind.is_null() {None} else {Some(kind.get_string().unwrap().to_string())},
+            perm:get_int32(row, 11).unwrap_or(0) as u32,
+            nlink:get_int32(row, 12).unwrap_or(0) as u32,
+            uid:get_int32(row, 13).unwrap_or(0) as u32,
+            gid:get_int32(row, 14).unwrap_or(0) as u32,
+            rdev:get_int32(row, 15).unwrap_or(0) as u32,
+            flags:get_int32(row, 16).unwrap_or(0) as u32,
+            project:get_int64(row, 17).unwrap_or(0) as u32,
+            inline_data:if inline_data.is_null() {None} else {inline_data.get_bytes().ok()},
+        }
+    }
+
+    /// A placeholder reserved by allocate_inode that create never filled in.
+    pub fn is_placeholder(&self) -> bool {
+        self.parent_inode.is_none() && self.kind.is_none()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind.as_ref().map(|k| k.as_str() == "dir").unwrap_or(false)
+    }
+
+    /// What getattr and lookup hand the kernel.
+    pub fn to_attr(&self) -> FileAttr {
+        let kind = match self.kind.as_ref().map(|k| k.as_str()) {
+            Some("dir") => FileType::Directory,
+            Some("symlink") => FileType::Symlink,
+            _ => FileType::RegularFile,
+        };
+        //the size of a directory follows its entries rather than being stored
+        let (size, blocks) = if kind == FileType::Directory {
+            let size = dir_size(&self.dir_contents);
+            (size, (size + BLOCK_SIZE - 1) / BLOCK_SIZE)
+        } else {
+            //blocks counts whole allocated chunks, a short last one doesn't fill its chunk
+            (self.size, cmp::min(self.blocks, (self.size + BLOCK_SIZE - 1) / BLOCK_SIZE))
+        };
+        FileAttr{
+            ino:self.inode,
+            size:size,
+            blocks:blocks,
+            atime:self.atime,
+            mtime:self.mtime,
+            ctime:self.ctime,
+            crtime:self.crtime,
+            kind:kind,
+            perm:(self.perm & 0o7777) as u16,
+            nlink:self.nlink,
+            uid:self.uid,
+            gid:self.gid,
+            rdev:self.rdev,
+            flags:self.flags,
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! POSIX byte-range locks shared by every client through Cassandra.
+//!
+//! All the locks on an inode live in one row of crustfs.locks, serialized
+//! into a text column, next to a version that every change bumps with a
+//! compare-and-set. That makes "check for conflicts, then take the lock" a
+//! single atomic step across clients without needing a row per lock.
+//!
+//! The kernel's lock_owner is only unique on one host, so locks are held by
+//! (client_id, lock_owner). Every lock carries a lease that the holding
+//! client keeps renewing from its session heartbeat; if the client dies, its
+//! locks simply expire and are dropped by whoever touches the row next. A
+//! client that is waiting on a lock doesn't have to sit out the lease: once
+//! the holder's session row is gone its locks are reclaimed straight away.
+//!
+//! A blocking setlk that can't be granted right away doesn't hold up the fuse
+//! loop, which has to go on serving the unlock it is waiting for. It is
+//! queued in lock_waiters instead, and a thread with its own connection keeps
+//! retrying it and replies once it goes through. setlk only fails with
+//! EDEADLK when waiting would close a cycle of owners waiting on each other;
+//! only waits queued on this mount are known, so a cycle across clients
+//! waits until one of them gives up.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libc::{c_int, EIO, EAGAIN, EDEADLK, EINTR};
+
+use cql_ffi::{CassSession, CassStatement, CassError};
+
+use fuse::ReplyEmpty;
+
+use time;
+
+use CrustFS;
+
+pub static F_RDLCK:u32 = 0;
+pub static F_WRLCK:u32 = 1;
+pub static F_UNLCK:u32 = 2;
+
+//seconds a lock survives without being renewed
+pub static LOCK_LEASE:i64 = 30;
+
+//backoff while a blocking setlk waits, in milliseconds
+static LOCK_RETRY_MIN:i64 = 10;
+static LOCK_RETRY_MAX:i64 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lock {
+    pub client:u64,
+    pub owner:u64,
+    pub start:u64,
+    pub end:u64,    //inclusive, u64::MAX means to EOF
+    pub typ:u32,
+    pub pid:u32,
+    pub expires:i64,
+}
+
+impl Lock {
+    fn same_owner(&self, other: &Lock) -> bool {
+        self.client == other.client && self.owner == other.owner
+    }
+
+    fn overlaps(&self, other: &Lock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        !self.same_owner(other) && self.overlaps(other) && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// A blocking setlk that is waiting for the lock in its way to go.
+pub struct LockWaiter {
+    ino:u64,
+    lock:Lock,
+    //(client, owner) holding the lock in the way when last tried
+    blocker:(u64, u64),
+    //when to try again, in milliseconds, and the backoff after that
+    retry:i64,
+    delay:i64,
+    reply:ReplyEmpty,
+}
+
+/// Blocking setlk requests not granted yet, shared with the waiter thread.
+pub fn new_lock_waiters() -> Arc<Mutex<Vec<LockWaiter>>> {
+    Arc::new(Mutex::new(Vec::new()))
+}
+
+fn now_ms() -> i64 {
+    let now = time::get_time();
+    now.sec * 1000 + now.nsec as i64 / 1000000
+}
+
+/// Whether owner waiting for blocker closes a cycle, given who the owners
+/// already waiting are waiting for.
+fn closes_cycle(waits: &[((u64, u64), (u64, u64))], owner: (u64, u64), blocker: (u64, u64)) -> bool {
+    let mut seen = HashSet::new();
+    let mut next = Some(blocker);
+    while let Some(current) = next {
+        if current == owner {
+            return true;
+        }
+        if !seen.insert(current) {
+            return false;
+        }
+        next = waits.iter().find(|&&(waiting, _)| waiting == current).map(|&(_, blocker)| blocker);
+    }
+    false
+}
+
+/// One lock per line: client owner start end typ pid expires
+fn parse_locks(text: &str) -> Vec<Lock> {
+    text.lines().filter_map(|line| {
+        let fields:Vec<u64> = line.split(' ').filter_map(|f| f.parse().ok()).collect();
+        if fields.len() != 7 {
+            error!("parse_locks: ignoring corrupt lock {:?}", line);
+            return None;
+        }
+        Some(Lock{client:fields[0], owner:fields[1], start:fields[2], end:fields[3],
+            typ:fields[4] as u32, pid:fields[5] as u32, expires:fields[6] as i64})
+    }).collect()
+}
+
+fn format_locks(locks: &[Lock]) -> String {
+    locks.iter().map(|l| format!("{} {} {} {} {} {} {}\n", l.client, l.owner, l.start, l.end, l.typ, l.pid, l.expires))
+        .collect::<Vec<String>>().concat()
+}
+
+/// Replace whatever lock.owner holds in lock's range with lock, splitting
+/// its existing locks around it. An F_UNLCK just punches the hole.
+fn apply_lock(locks: &mut Vec<Lock>, lock: &Lock) {
+    let mut result = Vec::new();
+    for held in locks.drain(..) {
+        if !held.same_owner(lock) || !held.overlaps(lock) {
+            result.push(held);
+            continue;
+        }
+        if held.start < lock.start {
+            result.push(Lock{end:lock.start - 1, ..held.clone()});
+        }
+        if held.end > lock.end {
+            result.push(Lock{start:lock.end + 1, ..held.clone()});
+        }
+    }
+    if lock.typ != F_UNLCK {
+        result.push(lock.clone());
+    }
+    *locks = result;
+}
+
+impl CrustFS {
+    /// Read-modify-write the locks of ino until our compare-and-set wins.
+    /// Expired locks are dropped before f sees them. If f fails nothing is written.
+    fn update_locks<F, R>(&self, ino: u64, mut f: F) -> Result<R, c_int>
+        where F: FnMut(&mut Vec<Lock>) -> Result<R, c_int> {
+        loop {
+            let (version, mut locks) = match self.select_locks(ino) {
+                Ok(current) => current,
+                Err(err) => {
+                    error!("update_locks: unable to read locks of {}: {:?}", ino, err);
+                    return Err(EIO);
+                }
+            };
+            let now = time::get_time().sec;
+            locks.retain(|l| l.expires > now);
+            let result = try!(f(&mut locks));
+
+            let statement = match version {
+                None => {
+                    let statement = CassStatement::new(self.cmds.insert_locks, 2);
+                    statement.bind_int64(0, ino as i64).unwrap();
+                    statement.bind_string(1, format_locks(&locks).as_str()).unwrap();
+                    statement
+                },
+                Some(version) => {
+                    let statement = CassStatement::new(self.cmds.update_locks, 4);
+                    statement.bind_string(0, format_locks(&locks).as_str()).unwrap();
+                    statement.bind_int64(1, version + 1).unwrap();
+                    statement.bind_int64(2, ino as i64).unwrap();
+                    statement.bind_int64(3, version).unwrap();
+                    statement
+                }
+            };
+            match self.execute_lwt(statement) {
+                Ok(true) => return Ok(result),
+                Ok(false) => debug!("update_locks: lost race on {}, retrying", ino),
+                Err(err) => {
+                    error!("update_locks: unable to write locks of {}: {:?}", ino, err);
+                    return Err(EIO);
+                }
+            }
+        }
+    }
+
+    fn select_locks(&self, ino: u64) -> Result<(Option<i64>, Vec<Lock>), CassError> {
+        let statement = CassStatement::new(self.cmds.select_locks, 1);
+        statement.bind_int64(0, ino as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(match result.first_row() {
+            None => (None, Vec::new()),
+            Some(row) => {
+                let version = row.get_column(0).unwrap().get_int64().unwrap();
+                let locks = row.get_column(1).unwrap();
+                (Some(version), if locks.is_null() {Vec::new()} else {parse_locks(&locks.get_string().unwrap().to_string())})
+            }
+        })
+    }
+
+    /// The first live lock held by someone else that would block lock, if any.
+    pub fn test_lock(&self, ino: u64, lock: &Lock) -> Result<Option<Lock>, c_int> {
+        match self.select_locks(ino) {
+            Ok((_, locks)) => {
+                let now = time::get_time().sec;
+                Ok(locks.into_iter().find(|held| held.expires > now && held.conflicts(lock)))
+            },
+            Err(err) => {
+                error!("test_lock: unable to read locks of {}: {:?}", ino, err);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Take, change or drop (F_UNLCK) a lock and reply. With wait, a lock
+    /// held by someone else queues the request for the waiter thread.
+    pub fn set_lock(&self, ino: u64, lock: Lock, wait: bool, reply: ReplyEmpty) {
+        match self.try_lock(ino, &lock) {
+            Ok(None) => reply.ok(),
+            Ok(Some(_)) if !wait => reply.error(EAGAIN),
+            Ok(Some(held)) => {
+                let mut waiters = self.lock_waiters.lock().unwrap();
+                let waits:Vec<((u64, u64), (u64, u64))> = waiters.iter()
+                    .map(|waiter| ((waiter.lock.client, waiter.lock.owner), waiter.blocker)).collect();
+                let blocker = (held.client, held.owner);
+                if closes_cycle(&waits, (lock.client, lock.owner), blocker) {
+                    debug!("set_lock: {:?} waiting for {:?} on {} would deadlock", (lock.client, lock.owner), blocker, ino);
+                    return reply.error(EDEADLK);
+                }
+                waiters.push(LockWaiter{ino:ino, lock:lock, blocker:blocker,
+                    retry:now_ms() + LOCK_RETRY_MIN, delay:LOCK_RETRY_MIN * 2, reply:reply});
+            },
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Take, change or drop a lock if nothing is in the way. Otherwise return
+    /// the live lock that is.
+    fn try_lock(&self, ino: u64, lock: &Lock) -> Result<Option<Lock>, c_int> {
+        loop {
+            let result = self.update_locks(ino, |locks| {
+                match locks.iter().find(|held| held.conflicts(lock)) {
+                    Some(held) => Ok(Some(held.clone())),
+                    None => {
+                        apply_lock(locks, lock);
+                        Ok(None)
+                    }
+                }
+            });
+            match result {
+                Ok(Some(held)) => {
+                    if held.client == self.client_id || !self.reclaim_locks(ino, held.client) {
+                        return Ok(Some(held));
+                    }
+                    debug!("set_lock: reclaimed locks of dead client {} on {}", held.client, ino);
+                },
+                Ok(None) => {
+                    if lock.typ != F_UNLCK {
+                        self.held_locks.lock().unwrap().insert(ino);
+                    }
+                    return Ok(None);
+                },
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Try the queued requests whose time has come, replying to those that
+    /// went through or failed.
+    fn retry_waiters(&self) {
+        let now = now_ms();
+        let due:Vec<LockWaiter> = {
+            let mut waiters = self.lock_waiters.lock().unwrap();
+            let (due, later):(Vec<LockWaiter>, Vec<LockWaiter>) = waiters.drain(..).partition(|waiter| waiter.retry <= now);
+            *waiters = later;
+            due
+        };
+        for mut waiter in due.into_iter() {
+            match self.try_lock(waiter.ino, &waiter.lock) {
+                Ok(None) => waiter.reply.ok(),
+                Ok(Some(held)) => {
+                    waiter.blocker = (held.client, held.owner);
+                    waiter.retry = now_ms() + waiter.delay;
+                    waiter.delay = if waiter.delay * 2 > LOCK_RETRY_MAX {LOCK_RETRY_MAX} else {waiter.delay * 2};
+                    self.lock_waiters.lock().unwrap().push(waiter);
+                },
+                Err(err) => waiter.reply.error(err),
+            }
+        }
+    }
+
+    /// Start the thread that grants queued blocking setlk requests. Like the
+    /// heartbeat it has a connection of its own.
+    pub fn start_lock_waiter(&self) {
+        let cluster_config = self.cluster_config.clone();
+        let held_locks = self.held_locks.clone();
+        let lock_waiters = self.lock_waiters.clone();
+        let client_id = self.client_id;
+        let consistency = self.consistency;
+        thread::spawn(move || {
+            let cluster = cluster_config.cluster();
+            let session = match CassSession::new().connect(&cluster).wait() {
+                Ok(session) => session,
+                Err(err) => {
+                    error!("lock waiter: unable to connect, blocking locks will wait forever: {:?}", err);
+                    return;
+                }
+            };
+            let mut crustfs = CrustFS::build(session);
+            crustfs.client_id = client_id;
+            crustfs.held_locks = held_locks;
+            crustfs.lock_waiters = lock_waiters;
+            crustfs.consistency = consistency;
+            loop {
+                thread::sleep_ms(LOCK_RETRY_MIN as u32);
+                crustfs.retry_waiters();
+            }
+        });
+    }
+
+    /// Drop every lock lock_owner holds on ino, as flush and release require,
+    /// and give up on any it is still waiting for.
+    pub fn release_locks(&self, ino: u64, owner: u64) -> Result<(), c_int> {
+        let client = self.client_id;
+        let abandoned:Vec<LockWaiter> = {
+            let mut waiters = self.lock_waiters.lock().unwrap();
+            let (abandoned, kept):(Vec<LockWaiter>, Vec<LockWaiter>) = waiters.drain(..)
+                .partition(|waiter| waiter.ino == ino && waiter.lock.owner == owner);
+            *waiters = kept;
+            abandoned
+        };
+        for waiter in abandoned.into_iter() {
+            waiter.reply.error(EINTR);
+        }
+        if !self.held_locks.lock().unwrap().contains(&ino) {
+            return Ok(());
+        }
+        let remaining = try!(self.update_locks(ino, |locks| {
+            locks.retain(|held| held.client != client || held.owner != owner);
+            Ok(locks.iter().any(|held| held.client == client))
+        }));
+        if !remaining {
+            self.held_locks.lock().unwrap().remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Drop the locks of client on ino if its session has expired. Returns
+    /// whether anything was reclaimed.
+    fn reclaim_locks(&self, ino: u64, client: u64) -> bool {
+        match self.session_alive(client) {
+            Ok(false) => {},
+            Ok(true) => return false,
+            Err(err) => {
+                error!("reclaim_locks: unable to look up session {}: {:?}", client, err);
+                return false;
+            }
+        }
+        self.update_locks(ino, |locks| {
+            let before = locks.len();
+            locks.retain(|held| held.client != client);
+            Ok(locks.len() != before)
+        }).unwrap_or(false)
+    }
+
+    /// Push the lease of all our locks on ino out by another LOCK_LEASE.
+    fn renew_locks(&self, ino: u64) -> Result<bool, c_int> {
+        let client = self.client_id;
+        self.update_locks(ino, |locks| {
+            let expires = time::get_time().sec + LOCK_LEASE;
+            let mut held = false;
+            for lock in locks.iter_mut().filter(|l| l.client == client) {
+                lock.expires = expires;
+                held = true;
+            }
+            Ok(held)
+        })
+    }
+
+    /// Called from the session heartbeat.
+    pub fn renew_all_locks(&self) {
+        let inodes:Vec<u64> = self.held_locks.lock().unwrap().iter().cloned().collect();
+        for ino in inodes.into_iter() {
+            match self.renew_locks(ino) {
+                Ok(true) => {},
+                Ok(false) => {self.held_locks.lock().unwrap().remove(&ino);},
+                Err(err) => error!("renew_all_locks: unable to renew locks on {}: {}", ino, err),
+            }
+        }
+    }
+}
+
+/// Inodes this client holds locks on, shared with the heartbeat thread.
+pub fn new_held_locks() -> Arc<Mutex<HashSet<u64>>> {
+    Arc::new(Mutex::new(HashSet::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lock, apply_lock, closes_cycle, parse_locks, format_locks, F_RDLCK, F_WRLCK, F_UNLCK};
+
+    fn lock(owner: u64, start: u64, end: u64, typ: u32) -> Lock {
+        Lock{client:1, owner:owner, start:start, end:end, typ:typ, pid:100, expires:0}
+    }
+
+    #[test]
+    fn unlock_splits_range() {
+        let mut locks = vec![lock(1, 0, 99, F_WRLCK)];
+        apply_lock(&mut locks, &lock(1, 10, 19, F_UNLCK));
+        assert_eq!(locks, vec![lock(1, 0, 9, F_WRLCK), lock(1, 20, 99, F_WRLCK)]);
+    }
+
+    #[test]
+    fn conflicts() {
+        assert!(lock(1, 0, 10, F_WRLCK).conflicts(&lock(2, 10, 20, F_RDLCK)));
+        assert!(!lock(1, 0, 10, F_RDLCK).conflicts(&lock(2, 0, 10, F_RDLCK)));
+        assert!(!lock(1, 0, 10, F_WRLCK).conflicts(&lock(1, 0, 10, F_WRLCK)));
+        assert!(!lock(1, 0, 9, F_WRLCK).conflicts(&lock(2, 10, 20, F_WRLCK)));
+    }
+
+    #[test]
+    fn deadlock_needs_a_cycle() {
+        //two owners on the same client contending isn't a deadlock by itself
+        assert!(!closes_cycle(&[], (1, 1), (1, 2)));
+        //but 2 already waiting for 1 is
+        assert!(closes_cycle(&[((1, 2), (1, 1))], (1, 1), (1, 2)));
+        assert!(closes_cycle(&[((1, 2), (2, 7)), ((2, 7), (1, 1))], (1, 1), (1, 2)));
+        assert!(!closes_cycle(&[((1, 2), (2, 7)), ((2, 7), (1, 3))], (1, 1), (1, 2)));
+        //a cycle we aren't part of doesn't hang the check
+        assert!(!closes_cycle(&[((1, 2), (1, 3)), ((1, 3), (1, 2))], (1, 1), (1, 2)));
+    }
+
+    #[test]
+    fn serialization() {
+        let locks = vec![lock(1, 0, 9, F_WRLCK), lock(2, 20, ::std::u64::MAX, F_RDLCK)];
+        assert_eq!(parse_locks(&format_locks(&locks)), locks);
+    }
+}
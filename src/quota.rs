@@ -0,0 +1,275 @@
+//! Block and inode quotas.
+//!
+//! Every inode is charged to its uid, its gid and, if it has one, its
+//! project. The project is inherited from the parent directory when the
+//! inode is made, so it caps a whole subtree no matter who writes into it.
+//! Project 0 means none.
+//!
+//! Usage lives in the crustfs.quota_usage counter table and limits in
+//! crustfs.quota_limits, both keyed by (kind, id). A limit of 0 means
+//! unlimited. Once usage passes a soft limit the time is recorded in
+//! *_over_since, and after the grace period the soft limit is enforced like
+//! a hard one until usage drops back under it.
+//!
+//! Anything that grows a file or allocates an inode calls check_quota before
+//! touching the inode table, and update_quota_usage once it has succeeded.
+//! Bytes are the blocks files take up, not their sizes, see data.rs.
+//! File growth is only charged when its write buffer is flushed, so
+//! check_quota counts what this mount's buffers hold on top of the stored
+//! usage; otherwise one long write could run far past a hard limit.
+
+use libc::{c_int, EDQUOT, EIO};
+
+use cql_ffi::{CassStatement, CassError};
+
+use time;
+
+use CrustFS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaKind {
+    User,
+    Group,
+    Project,
+}
+
+impl QuotaKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            QuotaKind::User => "user",
+            QuotaKind::Group => "group",
+            QuotaKind::Project => "project",
+        }
+    }
+
+    pub fn from_str(kind: &str) -> Option<QuotaKind> {
+        match kind {
+            "user" => Some(QuotaKind::User),
+            "group" => Some(QuotaKind::Group),
+            "project" => Some(QuotaKind::Project),
+            _ => None,
+        }
+    }
+}
+
+//one week, like the default in quota(1)
+pub static DEFAULT_GRACE:i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+pub struct QuotaLimits {
+    pub bytes_soft:u64,
+    pub bytes_hard:u64,
+    pub inodes_soft:u64,
+    pub inodes_hard:u64,
+    pub grace:i64,
+    pub bytes_over_since:i64,
+    pub inodes_over_since:i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaUsage {
+    pub bytes:i64,
+    pub inodes:i64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Within,
+    //back under the soft limit, forget when we went over
+    Clear,
+    //just went over the soft limit, start the grace period
+    StartGrace,
+    InGrace,
+    Exceeded,
+}
+
+fn verdict(wanted: i64, soft: u64, hard: u64, over_since: i64, grace: i64, now: i64) -> Verdict {
+    if hard > 0 && wanted > hard as i64 {
+        Verdict::Exceeded
+    } else if soft > 0 && wanted > soft as i64 {
+        if over_since == 0 {
+            Verdict::StartGrace
+        } else if now - over_since >= grace {
+            Verdict::Exceeded
+        } else {
+            Verdict::InGrace
+        }
+    } else if over_since != 0 {
+        Verdict::Clear
+    } else {
+        Verdict::Within
+    }
+}
+
+fn quota_ids(uid: u32, gid: u32, project: u32) -> Vec<(QuotaKind, u32)> {
+    let mut ids = vec![(QuotaKind::User, uid), (QuotaKind::Group, gid)];
+    if project != 0 {
+        ids.push((QuotaKind::Project, project));
+    }
+    ids
+}
+
+impl CrustFS {
+    pub fn quota_limits(&self, kind: QuotaKind, id: u32) -> Result<Option<QuotaLimits>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_quota_limits, 2);
+        statement.bind_string(0, kind.as_str()).unwrap();
+        statement.bind_int64(1, id as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.first_row().map(|row| {
+            let get = |idx| {
+                let column = row.get_column(idx).unwrap();
+                if column.is_null() {0} else {column.get_int64().unwrap()}
+            };
+            QuotaLimits{
+                bytes_soft:get(0) as u64,
+                bytes_hard:get(1) as u64,
+                inodes_soft:get(2) as u64,
+                inodes_hard:get(3) as u64,
+                grace:get(4),
+                bytes_over_since:get(5),
+                inodes_over_since:get(6),
+            }
+        }))
+    }
+
+    pub fn set_quota_limits(&self, kind: QuotaKind, id: u32, limits: &QuotaLimits) -> Result<(), CassError> {
+        let statement = CassStatement::new(self.cmds.insert_quota_limits, 9);
+        statement.bind_string(0, kind.as_str()).unwrap();
+        statement.bind_int64(1, id as i64).unwrap();
+        statement.bind_int64(2, limits.bytes_soft as i64).unwrap();
+        statement.bind_int64(3, limits.bytes_hard as i64).unwrap();
+        statement.bind_int64(4, limits.inodes_soft as i64).unwrap();
+        statement.bind_int64(5, limits.inodes_hard as i64).unwrap();
+        statement.bind_int64(6, limits.grace).unwrap();
+        statement.bind_int64(7, limits.bytes_over_since).unwrap();
+        statement.bind_int64(8, limits.inodes_over_since).unwrap();
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    pub fn quota_usage(&self, kind: QuotaKind, id: u32) -> Result<QuotaUsage, CassError> {
+        let statement = CassStatement::new(self.cmds.select_quota_usage, 2);
+        statement.bind_string(0, kind.as_str()).unwrap();
+        statement.bind_int64(1, id as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(match result.first_row() {
+            None => QuotaUsage{bytes:0, inodes:0},
+            Some(row) => QuotaUsage{
+                bytes:row.get_column(0).unwrap().get_int64().unwrap_or(0),
+                inodes:row.get_column(1).unwrap().get_int64().unwrap_or(0),
+            }
+        })
+    }
+
+    pub fn charge_quota(&self, kind: QuotaKind, id: u32, bytes: i64, inodes: i64) -> Result<(), CassError> {
+        let statement = CassStatement::new(self.cmds.update_quota_usage, 4);
+        statement.bind_int64(0, bytes).unwrap();
+        statement.bind_int64(1, inodes).unwrap();
+        statement.bind_string(2, kind.as_str()).unwrap();
+        statement.bind_int64(3, id as i64).unwrap();
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    /// Apply a usage delta to the owner's user and group quotas, and the project's.
+    pub fn update_quota_usage(&self, uid: u32, gid: u32, project: u32, bytes: i64, inodes: i64)
+                              -> Result<(), CassError> {
+        if bytes == 0 && inodes == 0 {
+            return Ok(());
+        }
+        for &(kind, id) in quota_ids(uid, gid, project).iter() {
+            try!(self.charge_quota(kind, id, bytes, inodes));
+        }
+        Ok(())
+    }
+
+    /// Fails with EDQUOT if growing by bytes and inodes would break a hard
+    /// limit, or a soft limit whose grace period has run out.
+    pub fn check_quota(&self, uid: u32, gid: u32, project: u32, bytes: i64, inodes: i64) -> Result<(), c_int> {
+        if bytes <= 0 && inodes <= 0 {
+            return Ok(());
+        }
+        for &(kind, id) in quota_ids(uid, gid, project).iter() {
+            try!(self.check_one_quota(kind, id, bytes, inodes));
+        }
+        Ok(())
+    }
+
+    /// Growth waiting in our write buffers that kind/id will be charged for.
+    fn buffered_growth(&self, kind: QuotaKind, id: u32) -> i64 {
+        self.write_buffers.values().filter(|buffer| {
+            let (uid, gid, project) = buffer.owner();
+            quota_ids(uid, gid, project).contains(&(kind, id))
+        }).fold(0, |growth, buffer| growth + buffer.uncharged(self.inline_max))
+    }
+
+    fn check_one_quota(&self, kind: QuotaKind, id: u32, bytes: i64, inodes: i64) -> Result<(), c_int> {
+        let mut limits = match self.quota_limits(kind, id) {
+            Ok(Some(limits)) => limits,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                error!("check_quota: {} {}: {:?}", kind.as_str(), id, err);
+                return Err(EIO);
+            }
+        };
+        let usage = match self.quota_usage(kind, id) {
+            Ok(usage) => usage,
+            Err(err) => {
+                error!("check_quota: {} {}: {:?}", kind.as_str(), id, err);
+                return Err(EIO);
+            }
+        };
+        let now = time::get_time().sec;
+        let used_bytes = usage.bytes + self.buffered_growth(kind, id);
+        let bytes_verdict = verdict(used_bytes + bytes, limits.bytes_soft, limits.bytes_hard,
+            limits.bytes_over_since, limits.grace, now);
+        let inodes_verdict = verdict(usage.inodes + inodes, limits.inodes_soft, limits.inodes_hard,
+            limits.inodes_over_since, limits.grace, now);
+        debug!("check_quota: {} {}: bytes {:?}, inodes {:?}", kind.as_str(), id, bytes_verdict, inodes_verdict);
+
+        //a delta of 0 can't push us over, so only judge the resource that grows
+        if (bytes > 0 && bytes_verdict == Verdict::Exceeded) || (inodes > 0 && inodes_verdict == Verdict::Exceeded) {
+            return Err(EDQUOT);
+        }
+        let mut changed = false;
+        match bytes_verdict {
+            Verdict::StartGrace => {limits.bytes_over_since = now; changed = true},
+            Verdict::Clear => {limits.bytes_over_since = 0; changed = true},
+            _ => {}
+        }
+        match inodes_verdict {
+            Verdict::StartGrace => {limits.inodes_over_since = now; changed = true},
+            Verdict::Clear => {limits.inodes_over_since = 0; changed = true},
+            _ => {}
+        }
+        if changed {
+            if let Err(err) = self.set_quota_limits(kind, id, &limits) {
+                error!("check_quota: unable to record grace period: {:?}", err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verdict, Verdict};
+
+    #[test]
+    fn soft_limit_grace() {
+        //under both limits
+        assert_eq!(verdict(10, 100, 200, 0, 60, 1000), Verdict::Within);
+        //crossing the soft limit starts the clock, and is allowed until it runs out
+        assert_eq!(verdict(150, 100, 200, 0, 60, 1000), Verdict::StartGrace);
+        assert_eq!(verdict(150, 100, 200, 1000, 60, 1059), Verdict::InGrace);
+        assert_eq!(verdict(150, 100, 200, 1000, 60, 1060), Verdict::Exceeded);
+        //dropping back under resets it
+        assert_eq!(verdict(50, 100, 200, 1000, 60, 1060), Verdict::Clear);
+    }
+
+    #[test]
+    fn hard_limit() {
+        assert_eq!(verdict(201, 100, 200, 0, 60, 1000), Verdict::Exceeded);
+        assert_eq!(verdict(201, 0, 0, 0, 60, 1000), Verdict::Within);
+    }
+}
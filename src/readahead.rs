@@ -0,0 +1,163 @@
+//! Read-ahead and parallel chunk fetching.
+//!
+//! The chunks a read needs are all requested at once and only then waited
+//! for, so a large read costs about one round trip instead of one per chunk.
+//! For every open file handle we remember where the last read ended; a read
+//! that starts there is sequential and doubles the read-ahead window (up to
+//! READAHEAD_MAX chunks), anything else drops it back to nothing. Chunks
+//! inside the window are requested in the background and kept, together with
+//! what was already read, in a small per-handle page cache.
+//!
+//! The page cache of every handle on a file is dropped whenever the file is
+//! written through this mount or the change log says another client changed it.
+
+use std::collections::BTreeMap;
+
+use libc::{c_int, EIO};
+
+use cql_ffi::{CassStatement, CassError};
+use cql_ffi::result_future::ResultFuture;
+
+use data::CHUNK_SIZE;
+use CrustFS;
+
+//read-ahead window, in chunks
+pub static READAHEAD_MIN:u64 = 4;
+pub static READAHEAD_MAX:u64 = 32;
+//handles with read state at once; past this the least recently read one is dropped
+pub static MAX_READ_FILES:usize = 32;
+
+struct Fetch {
+    //kept alive until the result is in
+    #[allow(dead_code)]
+    statement:CassStatement,
+    future:ResultFuture,
+}
+
+pub struct ReadState {
+    //where a sequential read would start next
+    next:u64,
+    window:u64,
+    pages:BTreeMap<u64, Vec<u8>>,
+    fetching:BTreeMap<u64, Fetch>,
+    last_used:u64,
+}
+
+impl ReadState {
+    fn new() -> ReadState {
+        ReadState{next:0, window:0, pages:BTreeMap::new(), fetching:BTreeMap::new(), last_used:0}
+    }
+}
+
+fn chunk_data(fetch: &mut Fetch) -> Result<Vec<u8>, CassError> {
+    let result = try!(fetch.future.wait());
+    //a chunk that was never written is a hole
+    Ok(result.first_row().map(|row| row.get_column(0).unwrap().get_bytes().unwrap()).unwrap_or(Vec::new()))
+}
+
+impl CrustFS {
+    fn start_fetch(&self, ino: u64, chunk: u64) -> Fetch {
+        let statement = CassStatement::new(self.cmds.select_chunk, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_int64(1, chunk as i64).unwrap();
+        let future = self.read_data(&statement);
+        Fetch{statement:statement, future:future}
+    }
+
+    /// The read state of fh, taken out of its handle for the length of a read.
+    /// A read on an fh we don't know gets a fresh one.
+    fn take_read_state(&mut self, fh: u64) -> ReadState {
+        self.read_clock += 1;
+        let mut state = self.handles.get_mut(&fh).and_then(|handle| handle.read.take()).unwrap_or_else(ReadState::new);
+        state.last_used = self.read_clock;
+        state
+    }
+
+    fn put_read_state(&mut self, fh: u64, state: ReadState) {
+        match self.handles.get_mut(&fh) {
+            Some(handle) => handle.read = Some(state),
+            None => return,
+        }
+        let reading = self.handles.values().filter(|handle| handle.read.is_some()).count();
+        if reading > MAX_READ_FILES {
+            let oldest = self.handles.iter().filter_map(|(fh, handle)| handle.read.as_ref().map(|read| (read.last_used, *fh)))
+                .min().map(|(_, fh)| fh);
+            if let Some(oldest) = oldest {
+                self.handles.get_mut(&oldest).unwrap().read = None;
+            }
+        }
+    }
+
+    /// Forget what we read of ino, because it changed.
+    pub fn drop_pages(&mut self, ino: u64) {
+        for handle in self.handles.values_mut().filter(|handle| handle.ino == ino) {
+            handle.read = None;
+        }
+    }
+
+    pub fn drop_all_pages(&mut self) {
+        for handle in self.handles.values_mut() {
+            handle.read = None;
+        }
+    }
+
+    /// Chunks first..last (inclusive) of ino as stored, fetched in parallel,
+    /// then start read-ahead for fh past them. file_size bounds the read-ahead.
+    pub fn fetch_chunks(&mut self, fh: u64, ino: u64, offset: u64, first: u64, last: u64, end: u64, file_size: u64)
+                        -> Result<BTreeMap<u64, Vec<u8>>, c_int> {
+        let last_chunk = (file_size + CHUNK_SIZE - 1) / CHUNK_SIZE;
+        let mut state = self.take_read_state(fh);
+        state.window = if offset == state.next {
+            if state.window == 0 {READAHEAD_MIN} else {::std::cmp::min(state.window * 2, READAHEAD_MAX)}
+        } else {
+            //random access: read-ahead would only waste bandwidth and memory
+            state.fetching.clear();
+            state.pages.clear();
+            0
+        };
+        state.next = end;
+
+        //everything this read needs and the read-ahead go out before we wait for any of it
+        let ahead = last + 1..::std::cmp::min(last + 1 + state.window, last_chunk);
+        for chunk in (first..last + 1).chain(ahead) {
+            if !state.pages.contains_key(&chunk) && !state.fetching.contains_key(&chunk) {
+                let fetch = self.start_fetch(ino, chunk);
+                state.fetching.insert(chunk, fetch);
+            }
+        }
+
+        let mut chunks = BTreeMap::new();
+        let mut failed = None;
+        for chunk in first..last + 1 {
+            let data = match state.pages.remove(&chunk) {
+                Some(data) => data,
+                None => {
+                    let mut fetch = state.fetching.remove(&chunk).unwrap();
+                    match chunk_data(&mut fetch) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("read: unable to read chunk {} of {}: {:?}", chunk, ino, err);
+                            failed = Some(EIO);
+                            break;
+                        }
+                    }
+                }
+            };
+            chunks.insert(chunk, data);
+        }
+        //keep the last chunk, the next sequential read usually starts inside it
+        if let Some(data) = chunks.get(&last) {
+            state.pages.insert(last, data.clone());
+        }
+        //whatever is behind the reader won't be asked for again
+        let behind:Vec<u64> = state.pages.keys().cloned().filter(|chunk| *chunk < last).collect();
+        for chunk in behind.into_iter() {
+            state.pages.remove(&chunk);
+        }
+        self.put_read_state(fh, state);
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(chunks),
+        }
+    }
+}
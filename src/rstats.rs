@@ -0,0 +1,270 @@
+//! Recursive statistics of directory subtrees.
+//!
+//! Every directory has counters in crustfs.rstats for the bytes, files (any
+//! inode that isn't a directory) and subdirectories anywhere below it, and a
+//! row in crustfs.rctimes with the newest ctime of itself or anything below
+//! it. They are read through the virtual attributes crustfs.rbytes,
+//! crustfs.rfiles, crustfs.rsubdirs and crustfs.rctime of a directory, e.g.
+//! `getfattr -n crustfs.rbytes dir`, which answers `du -s` without a walk.
+//!
+//! Keeping them up to date is lazy. Whenever an inode is created or its size
+//! or ctime changes, the change is collected in memory against that inode.
+//! Every RSTAT_FLUSH seconds (or once RSTAT_BATCH inodes are waiting, on
+//! unmount, and before one of the attributes is read) the changes are summed
+//! up the parent_inode chain, so a directory that many changes pass through
+//! costs one counter update per flush. The rctimes are written with their
+//! own time as the write timestamp, so of two racing clients the newer time
+//! wins whichever write arrives last.
+//!
+//! Changes still sitting in a write buffer, or in another client's memory,
+//! show up once they're flushed. Counters only add up what mounts told them:
+//! filesystems created before this existed start at zero until
+//! fsck.crustfs --repair fills them in.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+
+use libc::{c_int, EIO, ENODATA};
+
+use cql_ffi::{CassStatement, CassError};
+use cql_ffi::result_future::ResultFuture;
+
+use inode::InodeRow;
+use time::{self, Timespec};
+
+use {CrustFS, ROOT_INODE};
+
+//seconds a change may wait before it is added up the tree
+pub static RSTAT_FLUSH:i64 = 10;
+//inodes with waiting changes, past which they're added up right away
+pub static RSTAT_BATCH:usize = 1024;
+
+pub static RBYTES:&'static str = "crustfs.rbytes";
+pub static RFILES:&'static str = "crustfs.rfiles";
+pub static RSUBDIRS:&'static str = "crustfs.rsubdirs";
+pub static RCTIME:&'static str = "crustfs.rctime";
+
+/// What one or more changes add to the statistics of every directory above.
+#[derive(Debug, Clone, Copy)]
+struct Delta {
+    bytes:i64,
+    files:i64,
+    subdirs:i64,
+    ctime:Option<Timespec>,
+}
+
+impl Delta {
+    fn new() -> Delta {
+        Delta{bytes:0, files:0, subdirs:0, ctime:None}
+    }
+
+    fn add(&mut self, other: &Delta) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+        self.subdirs += other.subdirs;
+        self.ctime = cmp::max(self.ctime, other.ctime);
+    }
+
+    fn counts(&self) -> bool {
+        self.bytes != 0 || self.files != 0 || self.subdirs != 0
+    }
+}
+
+/// Changes not added up the tree yet, by the inode they happened to.
+pub struct RStatChanges {
+    pending:HashMap<u64, Delta>,
+    //when pending was last added up
+    flushed:i64,
+}
+
+impl RStatChanges {
+    pub fn new() -> RStatChanges {
+        RStatChanges{pending:HashMap::new(), flushed:time::get_time().sec}
+    }
+}
+
+/// The statistics of one directory as stored.
+#[derive(Debug, PartialEq)]
+pub struct SubtreeStats {
+    pub bytes:u64,
+    pub files:u64,
+    pub subdirs:u64,
+    pub ctime:Option<Timespec>,
+}
+
+pub fn is_rstat_xattr(name: &str) -> bool {
+    name == RBYTES || name == RFILES || name == RSUBDIRS || name == RCTIME
+}
+
+//counters can go negative if a delta got applied twice
+fn count(value: i64) -> u64 {
+    if value < 0 {0} else {value as u64}
+}
+
+/// Cassandra write timestamps are in microseconds.
+fn write_timestamp(time: Timespec) -> i64 {
+    time.sec * 1000000 + time.nsec as i64 / 1000
+}
+
+impl CrustFS {
+    /// Note that ino grew by bytes (or was created, with files or subdirs
+    /// set) and that its ctime is now ctime.
+    pub fn note_rstats(&mut self, ino: u64, bytes: i64, files: i64, subdirs: i64, ctime: Timespec) {
+        let delta = Delta{bytes:bytes, files:files, subdirs:subdirs, ctime:Some(ctime)};
+        self.rstat_changes.pending.entry(ino).or_insert_with(Delta::new).add(&delta);
+        self.flush_expired_rstats();
+    }
+
+    pub fn flush_expired_rstats(&mut self) {
+        if self.rstat_changes.pending.len() >= RSTAT_BATCH
+            || time::get_time().sec - self.rstat_changes.flushed >= RSTAT_FLUSH {
+            self.flush_rstats();
+        }
+    }
+
+    /// Whether ino is a directory, and its parent (None for the root or an
+    /// inode we can't read), remembered in parents for the length of a flush.
+    fn rstat_parent(&self, parents: &mut HashMap<u64, (bool, Option<u64>)>, ino: u64) -> (bool, Option<u64>) {
+        if let Some(known) = parents.get(&ino) {
+            return *known;
+        }
+        let found = match self.get_inode(ino) {
+            Ok(Some(ref row)) if !row.is_placeholder() =>
+                (row.is_dir(), if ino == ROOT_INODE {None} else {row.parent_inode}),
+            Ok(_) => (false, None),
+            Err(err) => {
+                error!("flush_rstats: unable to read inode {}: {:?}", ino, err);
+                (false, None)
+            }
+        };
+        parents.insert(ino, found);
+        found
+    }
+
+    /// Add every waiting change to all the directories above it.
+    pub fn flush_rstats(&mut self) {
+        let changes:Vec<(u64, Delta)> = self.rstat_changes.pending.drain().collect();
+        self.rstat_changes.flushed = time::get_time().sec;
+        if changes.is_empty() {
+            return;
+        }
+        debug!("flush_rstats: adding up changes to {} inodes", changes.len());
+        let mut parents = HashMap::new();
+        let mut totals:HashMap<u64, Delta> = HashMap::new();
+        for (ino, delta) in changes.into_iter() {
+            let (is_dir, mut dir) = self.rstat_parent(&mut parents, ino);
+            //a directory's own ctime is part of its rctime
+            if is_dir {
+                totals.entry(ino).or_insert_with(Delta::new).add(&Delta{ctime:delta.ctime, ..Delta::new()});
+            }
+            //only a damaged tree has a loop, but it mustn't hang the mount
+            let mut seen = HashSet::new();
+            while let Some(ancestor) = dir {
+                if !seen.insert(ancestor) {
+                    error!("flush_rstats: inode {} is its own ancestor", ancestor);
+                    break;
+                }
+                totals.entry(ancestor).or_insert_with(Delta::new).add(&delta);
+                dir = self.rstat_parent(&mut parents, ancestor).1;
+            }
+        }
+
+        let mut writes:Vec<(u64, CassStatement, ResultFuture)> = Vec::new();
+        for (dir, total) in totals.iter() {
+            if total.counts() {
+                let statement = self.rstats_statement(*dir, total.bytes, total.files, total.subdirs);
+                let future = self.write_meta(&statement);
+                writes.push((*dir, statement, future));
+            }
+            if let Some(ctime) = total.ctime {
+                let statement = self.rctime_statement(*dir, ctime);
+                let future = self.write_meta(&statement);
+                writes.push((*dir, statement, future));
+            }
+        }
+        //counter updates can't be repeated safely, a failed one is only logged
+        for (dir, _statement, mut future) in writes.into_iter() {
+            if let Err(err) = future.wait() {
+                error!("flush_rstats: unable to update statistics of {}: {:?}", dir, err);
+            }
+        }
+    }
+
+    fn rstats_statement(&self, dir: u64, bytes: i64, files: i64, subdirs: i64) -> CassStatement {
+        let statement = CassStatement::new(self.cmds.update_rstats, 4);
+        statement.bind_int64(0, bytes).unwrap();
+        statement.bind_int64(1, files).unwrap();
+        statement.bind_int64(2, subdirs).unwrap();
+        statement.bind_int64(3, dir as i64).unwrap();
+        statement
+    }
+
+    fn rctime_statement(&self, dir: u64, ctime: Timespec) -> CassStatement {
+        let statement = CassStatement::new(self.cmds.update_rctime, 4);
+        statement.bind_int64(0, write_timestamp(ctime)).unwrap();
+        statement.bind_int64(1, ctime.sec).unwrap();
+        statement.bind_int32(2, ctime.nsec).unwrap();
+        statement.bind_int64(3, dir as i64).unwrap();
+        statement
+    }
+
+    /// Add to the counters of dir right away, for mkfs and fsck.
+    pub fn charge_rstats(&self, dir: u64, bytes: i64, files: i64, subdirs: i64) -> Result<(), CassError> {
+        let statement = self.rstats_statement(dir, bytes, files, subdirs);
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    /// Move the rctime of dir up to ctime, unless it is newer already.
+    pub fn raise_rctime(&self, dir: u64, ctime: Timespec) -> Result<(), CassError> {
+        let statement = self.rctime_statement(dir, ctime);
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    pub fn rstats(&self, dir: u64) -> Result<SubtreeStats, CassError> {
+        let mut stats = SubtreeStats{bytes:0, files:0, subdirs:0, ctime:None};
+        let statement = CassStatement::new(self.cmds.select_rstats, 1);
+        statement.bind_int64(0, dir as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        if let Some(row) = result.first_row() {
+            stats.bytes = count(row.get_column(0).unwrap().get_int64().unwrap());
+            stats.files = count(row.get_column(1).unwrap().get_int64().unwrap());
+            stats.subdirs = count(row.get_column(2).unwrap().get_int64().unwrap());
+        }
+        let statement = CassStatement::new(self.cmds.select_rctime, 1);
+        statement.bind_int64(0, dir as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        if let Some(row) = result.first_row() {
+            let sec = row.get_column(0).unwrap().get_int64().unwrap();
+            stats.ctime = Some(Timespec::new(sec, row.get_column(1).unwrap().get_int32().unwrap()));
+        }
+        Ok(stats)
+    }
+
+    /// The value of one of the crustfs.r* attributes of row, as text.
+    pub fn get_rstat(&mut self, row: &InodeRow, name: &str) -> Result<Vec<u8>, c_int> {
+        if !row.is_dir() {
+            return Err(ENODATA);
+        }
+        //our own changes should be in what we hand out
+        self.flush_rstats();
+        let stats = match self.rstats(row.inode) {
+            Ok(stats) => stats,
+            Err(err) => {
+                error!("getxattr: unable to read statistics of {}: {:?}", row.inode, err);
+                return Err(EIO);
+            }
+        };
+        let value = match name {
+            n if n == RBYTES => stats.bytes.to_string(),
+            n if n == RFILES => stats.files.to_string(),
+            n if n == RSUBDIRS => stats.subdirs.to_string(),
+            _ => {
+                let ctime = cmp::max(stats.ctime.unwrap_or(row.ctime), row.ctime);
+                format!("{}.{:09}", ctime.sec, ctime.nsec)
+            }
+        };
+        Ok(value.into_bytes())
+    }
+}
@@ -0,0 +1,141 @@
+//! Registry of mounted clients.
+//!
+//! Every mount-crustfs writes a row to crustfs.sessions with a TTL and keeps
+//! rewriting it from a heartbeat thread, so the table always lists exactly the
+//! clients that are alive. Anything a client owns in the cluster (so far its
+//! locks) is tied to its client_id and can be reclaimed once the row is gone.
+
+use std::fs::File;
+use std::io::Read;
+use std::thread;
+
+use libc;
+
+use cql_ffi::{CassSession, CassStatement, CassError};
+
+use time;
+
+use CrustFS;
+
+//seconds a session row outlives its last heartbeat
+pub static SESSION_TTL:i64 = 30;
+
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub client_id:u64,
+    pub host:String,
+    pub pid:u32,
+    pub mounted:i64,
+    pub version:String,
+    pub mountpoint:String,
+}
+
+fn hostname() -> String {
+    let mut host = String::new();
+    match File::open("/proc/sys/kernel/hostname").and_then(|mut f| f.read_to_string(&mut host)) {
+        Ok(_) => host.trim().to_string(),
+        Err(_) => "unknown".to_string(),
+    }
+}
+
+impl CrustFS {
+    fn write_session(&self, info: &SessionInfo) -> Result<(), CassError> {
+        let statement = CassStatement::new(self.cmds.insert_session, 7);
+        statement.bind_int64(0, info.client_id as i64).unwrap();
+        statement.bind_string(1, info.host.as_str()).unwrap();
+        statement.bind_int32(2, info.pid as i32).unwrap();
+        statement.bind_int64(3, info.mounted).unwrap();
+        statement.bind_string(4, info.version.as_str()).unwrap();
+        statement.bind_string(5, info.mountpoint.as_str()).unwrap();
+        statement.bind_int32(6, SESSION_TTL as i32).unwrap();
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    /// Announce this mount to the cluster. The heartbeat started by init keeps it alive.
+    pub fn register_session(&self, mountpoint: &str) -> Result<(), CassError> {
+        let info = SessionInfo{
+            client_id:self.client_id,
+            host:hostname(),
+            pid:unsafe { libc::getpid() } as u32,
+            mounted:time::get_time().sec,
+            version:env!("CARGO_PKG_VERSION").to_string(),
+            mountpoint:mountpoint.to_string(),
+        };
+        try!(self.write_session(&info));
+        *self.session_info.lock().unwrap() = Some(info);
+        Ok(())
+    }
+
+    /// Remove our row on a clean unmount, so other clients reclaim what we held right away.
+    pub fn unregister_session(&self) -> Result<(), CassError> {
+        //held until the row is gone, so the heartbeat can't write it back in between
+        let mut session_info = self.session_info.lock().unwrap();
+        if session_info.take().is_none() {
+            return Ok(());
+        }
+        let statement = CassStatement::new(self.cmds.delete_session, 1);
+        statement.bind_int64(0, self.client_id as i64).unwrap();
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    pub fn list_sessions(&self) -> Result<Vec<SessionInfo>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_sessions, 0);
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.iter().map(|row| SessionInfo{
+            client_id:row.get_column(0).unwrap().get_int64().unwrap() as u64,
+            host:row.get_column(1).unwrap().get_string().unwrap().to_string(),
+            pid:row.get_column(2).unwrap().get_int32().unwrap() as u32,
+            mounted:row.get_column(3).unwrap().get_int64().unwrap(),
+            version:row.get_column(4).unwrap().get_string().unwrap().to_string(),
+            mountpoint:row.get_column(5).unwrap().get_string().unwrap().to_string(),
+        }).collect())
+    }
+
+    pub fn session_alive(&self, client_id: u64) -> Result<bool, CassError> {
+        let statement = CassStatement::new(self.cmds.select_session, 1);
+        statement.bind_int64(0, client_id as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.first_row().is_some())
+    }
+
+    /// Start the thread that keeps our session row and our lock leases alive,
+    /// and writes out access times that have waited long enough. It uses its
+    /// own connection, since the one in self belongs to the fuse loop.
+    pub fn start_heartbeat(&self) {
+        let cluster_config = self.cluster_config.clone();
+        let held_locks = self.held_locks.clone();
+        let client_id = self.client_id;
+        let session_info = self.session_info.clone();
+        let atimes = self.atimes.clone();
+        let consistency = self.consistency;
+        thread::spawn(move || {
+            let cluster = cluster_config.cluster();
+            let session = match CassSession::new().connect(&cluster).wait() {
+                Ok(session) => session,
+                Err(err) => {
+                    error!("heartbeat: unable to connect, session and locks will expire: {:?}", err);
+                    return;
+                }
+            };
+            let mut crustfs = CrustFS::build(session);
+            crustfs.client_id = client_id;
+            crustfs.held_locks = held_locks;
+            crustfs.atimes = atimes;
+            crustfs.consistency = consistency;
+            loop {
+                thread::sleep_ms((SESSION_TTL * 1000 / 3) as u32);
+                if let Some(ref info) = *session_info.lock().unwrap() {
+                    if let Err(err) = crustfs.write_session(info) {
+                        error!("heartbeat: unable to refresh session: {:?}", err);
+                    }
+                }
+                crustfs.renew_all_locks();
+                //nobody is reading an idle mount, so nothing else would write them
+                crustfs.flush_expired_atimes();
+                crustfs.sync_all();
+            }
+        });
+    }
+}
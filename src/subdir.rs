@@ -0,0 +1,101 @@
+//! Mounting a subdirectory.
+//!
+//! The kernel always knows the root of a FUSE mount as inode 1. With
+//! mount-crustfs --subdir the path is resolved once, at mount time, and root
+//! names the directory it leads to. Every inode number the kernel hands us
+//! goes through inner, which turns 1 into root, and every one we hand back
+//! through outer, which turns root into 1; all the others are the same on
+//! both sides. The kernel never learns a number for anything above root,
+//! and ".." of root, in lookup as in readdir, is root itself, so nothing
+//! outside the subtree can be reached through the mount.
+
+use libc::{c_int, EIO, ENOENT};
+
+use fuse::FileAttr;
+
+use CrustFS;
+
+//what the kernel calls the root of every FUSE mount
+pub static FUSE_ROOT_ID:u64 = 1;
+
+fn inner_ino(root: u64, ino: u64) -> u64 {
+    if ino == FUSE_ROOT_ID {root} else {ino}
+}
+
+fn outer_ino(root: u64, ino: u64) -> u64 {
+    if ino == root {FUSE_ROOT_ID} else {ino}
+}
+
+//".." of dir given its parent_inode column; an inode without one is its own parent
+fn parent_of(root: u64, dir: u64, parent: Option<u64>) -> u64 {
+    if dir == root {dir} else {parent.unwrap_or(dir)}
+}
+
+impl CrustFS {
+    /// The inode the kernel means by ino.
+    pub fn inner(&self, ino: u64) -> u64 {
+        inner_ino(self.root, ino)
+    }
+
+    /// The number the kernel knows ino by.
+    pub fn outer(&self, ino: u64) -> u64 {
+        outer_ino(self.root, ino)
+    }
+
+    pub fn outer_attr(&self, attr: &FileAttr) -> FileAttr {
+        let mut attr = attr.clone();
+        attr.ino = self.outer(attr.ino);
+        attr
+    }
+
+    /// What ".." of dir leads to, without leaving the mount.
+    pub fn parent_dir(&self, dir: u64) -> Result<u64, c_int> {
+        if dir == self.root {
+            return Ok(dir);
+        }
+        match self.get_inode(dir) {
+            Ok(Some(ref row)) if row.is_placeholder() => Err(ENOENT),
+            Ok(Some(row)) => Ok(parent_of(self.root, dir, row.parent_inode)),
+            Ok(None) => Err(ENOENT),
+            Err(err) => {
+                error!("lookup: unable to read inode {}: {:?}", dir, err);
+                Err(EIO)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inner_ino, outer_ino, parent_of, FUSE_ROOT_ID};
+
+    #[test]
+    fn whole_tree_mapping() {
+        for &ino in [FUSE_ROOT_ID, 2, 42].iter() {
+            assert_eq!(inner_ino(FUSE_ROOT_ID, ino), ino);
+            assert_eq!(outer_ino(FUSE_ROOT_ID, ino), ino);
+        }
+    }
+
+    #[test]
+    fn subdir_mapping() {
+        let root = 42;
+        assert_eq!(inner_ino(root, FUSE_ROOT_ID), root);
+        assert_eq!(outer_ino(root, root), FUSE_ROOT_ID);
+        assert_eq!(inner_ino(root, 7), 7);
+        assert_eq!(outer_ino(root, 7), 7);
+        assert_eq!(outer_ino(root, inner_ino(root, FUSE_ROOT_ID)), FUSE_ROOT_ID);
+    }
+
+    #[test]
+    fn dotdot_stays_in_the_mount() {
+        let root = 42;
+        //the real parent of the mounted directory is never handed out
+        assert_eq!(parent_of(root, root, Some(FUSE_ROOT_ID)), root);
+        assert_eq!(outer_ino(root, parent_of(root, root, Some(FUSE_ROOT_ID))), FUSE_ROOT_ID);
+        assert_eq!(parent_of(root, 7, Some(root)), root);
+        assert_eq!(outer_ino(root, parent_of(root, 7, Some(root))), FUSE_ROOT_ID);
+        assert_eq!(parent_of(root, 8, Some(7)), 7);
+        assert_eq!(parent_of(root, 8, None), 8);
+    }
+}
@@ -0,0 +1,130 @@
+//! Writes that have been sent to Cassandra but not yet waited for.
+//!
+//! Anything that doesn't have to be acknowledged before we reply to the
+//! kernel is queued here per inode with queue_write. flush, fsync and
+//! fsyncdir drain the queue and hand back the first error any of those
+//! writes hit, which is how a failed write eventually reaches close() or
+//! fsync(). When a stronger fsync level is configured, queued writes are
+//! sent at that level in the first place, so waiting for them is all fsync
+//! has to do. Each write is sent exactly once: sending one again later
+//! would give it a newer timestamp than writes queued after it, letting an
+//! old chunk or size win over a truncate, a rewrite or another client.
+
+use std::collections::HashMap;
+
+use libc::{c_int, EIO};
+
+use cql_ffi::CassStatement;
+use cql_ffi::result_future::ResultFuture;
+
+use consistency::OpClass;
+use CrustFS;
+
+//writes in flight per inode before queue_write waits for the oldest, which
+//keeps a large sequential write streaming without holding all of it in memory
+pub static MAX_IN_FLIGHT:usize = 32;
+
+pub struct PendingWrite {
+    class:OpClass,
+    //the driver needs it alive until the future is waited for
+    statement:CassStatement,
+    future:ResultFuture,
+}
+
+#[derive(Default)]
+pub struct Pending {
+    writes:Vec<PendingWrite>,
+    //first error seen and not yet reported
+    error:Option<c_int>,
+}
+
+pub fn new_pending() -> HashMap<u64, Pending> {
+    HashMap::new()
+}
+
+fn is_data(class: OpClass) -> bool {
+    match class {
+        OpClass::DataRead | OpClass::DataWrite | OpClass::Fsync => true,
+        _ => false,
+    }
+}
+
+impl CrustFS {
+    /// Send a write for ino without waiting for it. With an fsync level
+    /// configured it goes out at that level, for fsync to wait on.
+    pub fn queue_write(&mut self, ino: u64, class: OpClass, statement: CassStatement) {
+        let level = if self.consistency.fsync.is_some() {OpClass::Fsync} else {class};
+        let future = self.execute_as(level, &statement);
+        let oldest = {
+            let pending = self.pending.entry(ino).or_insert_with(Pending::default);
+            pending.writes.push(PendingWrite{class:class, statement:statement, future:future});
+            if pending.writes.len() > MAX_IN_FLIGHT {Some(pending.writes.remove(0))} else {None}
+        };
+        if let Some(write) = oldest {
+            self.finish_write(ino, write);
+        }
+    }
+
+    /// Wait for one write, recording a failure for the next flush or fsync.
+    fn finish_write(&mut self, ino: u64, mut write: PendingWrite) {
+        if let Err(err) = write.future.wait() {
+            error!("sync: write to inode {} failed: {:?}", ino, err);
+            self.defer_error(ino, EIO);
+        }
+    }
+
+    /// Wait for the writes queued for ino without reporting their errors yet,
+    /// for reads that need to see them.
+    pub fn wait_writes(&mut self, ino: u64) {
+        let writes:Vec<PendingWrite> = match self.pending.get_mut(&ino) {
+            Some(pending) => pending.writes.drain(..).collect(),
+            None => return,
+        };
+        for write in writes.into_iter() {
+            self.finish_write(ino, write);
+        }
+    }
+
+    /// Remember an error to report on the next flush or fsync of ino.
+    pub fn defer_error(&mut self, ino: u64, err: c_int) {
+        let pending = self.pending.entry(ino).or_insert_with(Pending::default);
+        if pending.error.is_none() {
+            pending.error = Some(err);
+        }
+    }
+
+    /// Wait for the writes queued for ino and report the first error since the
+    /// last call. With datasync, metadata-only writes are left queued.
+    pub fn sync_inode(&mut self, ino: u64, datasync: bool) -> Result<(), c_int> {
+        let writes = match self.pending.get_mut(&ino) {
+            Some(pending) => {
+                let (writes, kept):(Vec<PendingWrite>, Vec<PendingWrite>) = pending.writes.drain(..)
+                    .partition(|write| !datasync || is_data(write.class));
+                pending.writes = kept;
+                writes
+            },
+            None => return Ok(()),
+        };
+        for write in writes.into_iter() {
+            self.finish_write(ino, write);
+        }
+        let error = self.pending.get_mut(&ino).and_then(|pending| pending.error.take());
+        if self.pending.get(&ino).map(|pending| pending.writes.is_empty()).unwrap_or(false) {
+            self.pending.remove(&ino);
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait for everything still queued, on unmount.
+    pub fn sync_all(&mut self) {
+        let inodes:Vec<u64> = self.pending.keys().cloned().collect();
+        for ino in inodes.into_iter() {
+            if let Err(err) = self.sync_inode(ino, false) {
+                error!("sync_all: inode {} had unreported write errors: {}", ino, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Extended attributes, stored one row per (inode, name) in crustfs.xattr.
+//!
+//! Like Linux, the namespace prefix of the name decides who may touch it:
+//! user.* follows the file's read/write permission and is only allowed on
+//! regular files and directories, trusted.* is root only (and hidden from
+//! everyone else by listxattr), and security.* can be read by anyone but
+//! only written by root. Of system.* only the two POSIX ACLs are supported;
+//! they are validated on the way in and may only be set by the owner.
+//!
+//! crustfs.rbytes and friends aren't stored here: they are read-only views of
+//! a directory's subtree statistics (see rstats.rs), readable by anyone and
+//! left out of listxattr.
+
+use libc::{c_int, EIO, ENOENT, EPERM, EACCES, EEXIST, ENODATA, ERANGE, E2BIG, EINVAL, EOPNOTSUPP};
+
+use cql_ffi::{CassStatement, CassError};
+
+use access::{R_OK, W_OK};
+use acl::{Acl, ACL_ACCESS, ACL_DEFAULT};
+use inode::InodeRow;
+use rstats::is_rstat_xattr;
+use CrustFS;
+
+pub static XATTR_CREATE:u32 = 1;
+pub static XATTR_REPLACE:u32 = 2;
+
+//the same limits the Linux VFS enforces
+pub static XATTR_NAME_MAX:usize = 255;
+pub static XATTR_SIZE_MAX:usize = 65536;
+
+#[derive(Debug, PartialEq)]
+enum Namespace {
+    User,
+    Trusted,
+    Security,
+    System,
+}
+
+fn namespace(name: &str) -> Option<Namespace> {
+    if name.starts_with("user.") {
+        Some(Namespace::User)
+    } else if name.starts_with("trusted.") {
+        Some(Namespace::Trusted)
+    } else if name.starts_with("security.") {
+        Some(Namespace::Security)
+    } else if name == ACL_ACCESS || name == ACL_DEFAULT {
+        Some(Namespace::System)
+    } else {
+        None
+    }
+}
+
+/// Visible to listxattr for this uid.
+fn listable(name: &str, uid: u32) -> bool {
+    namespace(name) != Some(Namespace::Trusted) || uid == 0
+}
+
+/// setxattr can't be asked for an attribute that is both new and already there.
+fn check_flags(flags: u32) -> Result<(), c_int> {
+    if flags & XATTR_CREATE != 0 && flags & XATTR_REPLACE != 0 {Err(EINVAL)} else {Ok(())}
+}
+
+impl CrustFS {
+    /// May uid/gid read (or with write, modify) the attribute name on row?
+    fn check_xattr_access(&self, row: &InodeRow, uid: u32, gid: u32, name: &str, write: bool) -> Result<(), c_int> {
+        match namespace(name) {
+            None => Err(EOPNOTSUPP),
+            Some(Namespace::User) => {
+                if row.kind.as_ref().map(|k| k.as_str() != "file" && k.as_str() != "dir").unwrap_or(true) {
+                    return Err(if write {EPERM} else {ENODATA});
+                }
+                if try!(self.check_access(row, uid, gid, if write {W_OK} else {R_OK})) {Ok(())} else {Err(EACCES)}
+            },
+            Some(Namespace::Trusted) => if uid == 0 {Ok(())} else {Err(EPERM)},
+            Some(Namespace::Security) => if !write || uid == 0 {Ok(())} else {Err(EPERM)},
+            Some(Namespace::System) => {
+                if name == ACL_DEFAULT && !row.is_dir() {
+                    return Err(if write {EACCES} else {ENODATA});
+                }
+                if !write || uid == 0 || uid == row.uid {Ok(())} else {Err(EPERM)}
+            }
+        }
+    }
+
+    fn xattr_inode(&self, ino: u64) -> Result<InodeRow, c_int> {
+        match self.get_inode(ino) {
+            Ok(Some(row)) => Ok(row),
+            Ok(None) => Err(ENOENT),
+            Err(err) => {
+                error!("xattr: unable to read inode {}: {:?}", ino, err);
+                Err(EIO)
+            }
+        }
+    }
+
+    pub fn select_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_xattr, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_string(1, name).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.first_row().map(|row| row.get_column(0).unwrap().get_bytes().unwrap()))
+    }
+
+    fn select_xattr_names(&self, ino: u64) -> Result<Vec<String>, CassError> {
+        let statement = CassStatement::new(self.cmds.select_xattr_names, 1);
+        statement.bind_int64(0, ino as i64).unwrap();
+        let result = try!(self.read_meta(&statement).wait());
+        Ok(result.iter().map(|row| row.get_column(0).unwrap().get_string().unwrap().to_string()).collect())
+    }
+
+    pub fn get_xattr(&mut self, uid: u32, gid: u32, ino: u64, name: &str) -> Result<Vec<u8>, c_int> {
+        let row = try!(self.xattr_inode(ino));
+        if is_rstat_xattr(name) {
+            return self.get_rstat(&row, name);
+        }
+        try!(self.check_xattr_access(&row, uid, gid, name, false));
+        match self.select_xattr(ino, name) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(ENODATA),
+            Err(err) => {
+                error!("getxattr: {:?}", err);
+                Err(EIO)
+            }
+        }
+    }
+
+    pub fn set_xattr(&mut self, uid: u32, gid: u32, ino: u64, name: &str, value: &[u8], flags: u32) -> Result<(), c_int> {
+        try!(check_flags(flags));
+        if name.len() > XATTR_NAME_MAX {
+            return Err(ERANGE);
+        }
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(E2BIG);
+        }
+        let row = try!(self.xattr_inode(ino));
+        try!(self.check_xattr_access(&row, uid, gid, name, true));
+
+        //ACLs are stored the way we'd hand them back out, and the access ACL drives the mode bits
+        let acl = if namespace(name) == Some(Namespace::System) {Some(try!(Acl::parse(value)))} else {None};
+        let acl_bytes = acl.as_ref().map(|acl| acl.to_bytes());
+        let value = match acl_bytes {
+            Some(ref bytes) => &bytes[..],
+            None => value,
+        };
+
+        //XATTR_CREATE and XATTR_REPLACE are decided by the LWT, so two clients racing can't both win
+        let statement = if flags & XATTR_REPLACE != 0 {
+            let statement = CassStatement::new(self.cmds.update_xattr_if_exists, 3);
+            statement.bind_bytes(0, value).unwrap();
+            statement.bind_int64(1, ino as i64).unwrap();
+            statement.bind_string(2, name).unwrap();
+            statement
+        } else {
+            let cmd = if flags & XATTR_CREATE != 0 {
+                self.cmds.insert_xattr_if_not_exists
+            } else {
+                self.cmds.insert_xattr
+            };
+            let statement = CassStatement::new(cmd, 3);
+            statement.bind_int64(0, ino as i64).unwrap();
+            statement.bind_string(1, name).unwrap();
+            statement.bind_bytes(2, value).unwrap();
+            statement
+        };
+        match self.execute_lwt(statement) {
+            Ok(true) => match acl {
+                Some(ref acl) if name == ACL_ACCESS => self.update_perm(&row, (row.perm & !0o777) | acl.mode()),
+                _ => Ok(()),
+            },
+            Ok(false) => Err(if flags & XATTR_CREATE != 0 {EEXIST} else {ENODATA}),
+            Err(err) => {
+                error!("setxattr: {:?}", err);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Unconditionally store an attribute, bypassing permission checks. Used
+    /// when a new inode inherits its parent's default ACL.
+    pub fn store_xattr(&self, ino: u64, name: &str, value: &[u8]) -> Result<(), CassError> {
+        let statement = CassStatement::new(self.cmds.insert_xattr, 3);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_string(1, name).unwrap();
+        statement.bind_bytes(2, value).unwrap();
+        try!(self.write_meta(&statement).wait());
+        Ok(())
+    }
+
+    /// The names visible to uid, each terminated by a NUL as listxattr(2) wants them.
+    pub fn list_xattrs(&self, uid: u32, ino: u64) -> Result<Vec<u8>, c_int> {
+        try!(self.xattr_inode(ino));
+        let names = match self.select_xattr_names(ino) {
+            Ok(names) => names,
+            Err(err) => {
+                error!("listxattr: {:?}", err);
+                return Err(EIO);
+            }
+        };
+        let mut list = Vec::new();
+        for name in names.iter().filter(|name| listable(name.as_str(), uid)) {
+            list.extend(name.as_bytes().iter().cloned());
+            list.push(0);
+        }
+        Ok(list)
+    }
+
+    pub fn remove_xattr(&self, uid: u32, gid: u32, ino: u64, name: &str) -> Result<(), c_int> {
+        let row = try!(self.xattr_inode(ino));
+        try!(self.check_xattr_access(&row, uid, gid, name, true));
+        let statement = CassStatement::new(self.cmds.delete_xattr, 2);
+        statement.bind_int64(0, ino as i64).unwrap();
+        statement.bind_string(1, name).unwrap();
+        match self.execute_lwt(statement) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ENODATA),
+            Err(err) => {
+                error!("removexattr: {:?}", err);
+                Err(EIO)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::EINVAL;
+
+    use acl::{ACL_ACCESS, ACL_DEFAULT};
+    use super::{check_flags, listable, namespace, Namespace, XATTR_CREATE, XATTR_REPLACE};
+
+    #[test]
+    fn namespaces() {
+        assert_eq!(namespace("user.mime_type"), Some(Namespace::User));
+        assert_eq!(namespace("trusted.overlay"), Some(Namespace::Trusted));
+        assert_eq!(namespace("security.selinux"), Some(Namespace::Security));
+        assert_eq!(namespace(ACL_ACCESS), Some(Namespace::System));
+        assert_eq!(namespace(ACL_DEFAULT), Some(Namespace::System));
+        //only the two ACLs of system.*, and the prefix needs its dot
+        assert_eq!(namespace("system.nfs4_acl"), None);
+        assert_eq!(namespace("user"), None);
+        assert_eq!(namespace("other.name"), None);
+    }
+
+    #[test]
+    fn trusted_hidden_from_users() {
+        assert!(listable("user.a", 1000));
+        assert!(listable("security.selinux", 1000));
+        assert!(!listable("trusted.a", 1000));
+        assert!(listable("trusted.a", 0));
+    }
+
+    #[test]
+    fn create_and_replace_exclusive() {
+        assert_eq!(check_flags(0), Ok(()));
+        assert_eq!(check_flags(XATTR_CREATE), Ok(()));
+        assert_eq!(check_flags(XATTR_REPLACE), Ok(()));
+        assert_eq!(check_flags(XATTR_CREATE | XATTR_REPLACE), Err(EINVAL));
+    }
+}